@@ -14,9 +14,63 @@ fn main() -> Result<()> {
     .generate_to_out_dir(None)?;
 
     let contents = std::fs::read_to_string(&path)?;
-    let re = regex::Regex::new(r"(pub\s+enum\s+\w+)").unwrap();
+    // Enums are kebab-case unless listed here, a missing entry fails the build.
+    let enum_cases: &[(&str, &str)] = &[
+        // `containers[].depends_on[].condition`, e.g. `exited_successfully`.
+        ("ContainerDependencyCondition", "snake_case"),
+    ];
+    for (name, _) in enum_cases {
+        anyhow::ensure!(
+            contents.contains(&format!("pub enum {name} ")),
+            "enum {name} not found in generated bindings"
+        );
+    }
+    let re = regex::Regex::new(r"(pub\s+enum\s+(\w+))").unwrap();
+    let contents = re
+        .replace_all(&contents, |caps: &regex::Captures| {
+            let case = enum_cases
+                .iter()
+                .find(|(name, _)| *name == &caps[2])
+                .map_or("kebab-case", |(_, case)| case);
+            format!("#[serde(rename_all = \"{case}\")]\n{}", &caps[1])
+        })
+        .into_owned();
+    // Fields of type `option<list<tuple<string, T>>>` are JSON objects in the Machines API.
+    let re = regex::Regex::new(
+        r"(?m)^(\s*)(pub\s+\w+:\s*Option<_rt::Vec::<\(_rt::String,[^()]+,\)>>,)$",
+    )
+    .unwrap();
+    let contents = re
+        .replace_all(
+            &contents,
+            "$1#[serde(default, with = \"crate::serde_util::optional_map\")]\n$1$2",
+        )
+        .into_owned();
+    // The Machines API reports `autostop` as a bool if it is `off` or `stop`.
+    let re = regex::Regex::new(r"(?m)^(\s*)(pub\s+autostop:\s*Option<AutostopMode>,)$").unwrap();
+    let contents = re
+        .replace_all(
+            &contents,
+            "$1#[serde(default, with = \"crate::serde_util::optional_autostop\")]\n$1$2",
+        )
+        .into_owned();
+    // `stop-config.timeout` is a Go duration, a bare number would mean nanoseconds.
+    let re = regex::Regex::new(r"(?m)^(\s*)(pub\s+timeout:\s*Option<u64>,)$").unwrap();
+    let contents = re
+        .replace_all(
+            &contents,
+            "$1#[serde(default, with = \"crate::serde_util::optional_duration_secs\")]\n$1$2",
+        )
+        .into_owned();
+    // `machine.cordoned` is not reported by the Machines API, it is derived from the metadata.
+    let re = regex::Regex::new(r"(?m)^(\s*)(pub\s+cordoned:\s*bool,)$").unwrap();
+    let contents = re
+        .replace_all(&contents, "$1#[serde(default)]\n$1$2")
+        .into_owned();
+    // WIT field `%type` becomes `type_` in Rust.
+    let re = regex::Regex::new(r"(?m)^(\s*)(pub\s+type_:)").unwrap();
     let contents = re
-        .replace_all(&contents, "#[serde(rename_all = \"kebab-case\")]\n$1")
+        .replace_all(&contents, "$1#[serde(rename = \"type\")]\n$1$2")
         .into_owned();
     std::fs::write(&path, contents)?;
 
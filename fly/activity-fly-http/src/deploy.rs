@@ -0,0 +1,309 @@
+use crate::generated::exports::obelisk_flyio::activity_fly_http::deploy::{
+    self, DeployFailure, DeployReport, Strategy,
+};
+use crate::generated::exports::obelisk_flyio::activity_fly_http::machines::{
+    CheckState, CreateOptions, Machine, MachineState, WaitOutcome,
+};
+use crate::machine::WAIT_TIMEOUT_MAX_SECS;
+use crate::{AppName, InstanceId, MachineId, machine};
+use anyhow::{Context, bail, ensure};
+use wstd::runtime::block_on;
+use wstd::time::{Duration, Instant};
+
+/// Maximum time for the checks of a started machine to pass.
+const CHECKS_TIMEOUT_SECS: u64 = 300;
+const CHECKS_POLL_INTERVAL_SECS: u64 = 5;
+/// Machines updated one by one can each take up to `WAIT_TIMEOUT_MAX_SECS + CHECKS_TIMEOUT_SECS`,
+/// keep the call well within the 900s lock expiry configured for this activity. The rest is left `pending`.
+const MAX_MACHINES_PER_CALL: usize = 2;
+
+/// Deadline for a machine updated now to become healthy.
+fn healthy_deadline() -> Instant {
+    Instant::now() + Duration::from_secs(u64::from(WAIT_TIMEOUT_MAX_SECS) + CHECKS_TIMEOUT_SECS)
+}
+
+/// Replace the image in the raw configuration, leaving all other fields intact.
+fn with_image(
+    mut machine_config: serde_json::Value,
+    image: &str,
+) -> Result<serde_json::Value, anyhow::Error> {
+    machine_config
+        .as_object_mut()
+        .context("machine configuration must be an object")?
+        .insert(
+            "image".to_string(),
+            serde_json::Value::String(image.to_string()),
+        );
+    Ok(machine_config)
+}
+
+/// The update launches the machine unless told otherwise, keep machines that are not `started` down.
+fn update_options(state: MachineState) -> Option<CreateOptions> {
+    (!matches!(state, MachineState::Started)).then_some(CreateOptions {
+        skip_launch: Some(true),
+        skip_service_registration: None,
+        lease_ttl_secs: None,
+    })
+}
+
+/// Update the image of the machine, return the new instance ID if the machine should be started.
+async fn update_machine(
+    app_name: &AppName,
+    machine: &Machine,
+    image: &str,
+) -> Result<Option<InstanceId>, anyhow::Error> {
+    let machine_id = MachineId::new(machine.id.clone())?;
+    let machine_config = machine::get_config_raw(app_name.clone(), machine_id.clone()).await?;
+    let updated = machine::update_raw(
+        app_name.clone(),
+        machine_id,
+        with_image(machine_config, image)?,
+        None,
+        None,
+        update_options(machine.state),
+    )
+    .await?;
+    if matches!(machine.state, MachineState::Started) {
+        Ok(Some(InstanceId::new(updated.instance_id)?))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Wait until the machine is started and all its checks are passing, giving up at `deadline`.
+async fn wait_healthy(
+    app_name: &AppName,
+    machine_id: &str,
+    instance_id: InstanceId,
+    deadline: Instant,
+) -> Result<(), anyhow::Error> {
+    let machine_id = MachineId::new(machine_id.to_string())?;
+    let start_timeout_secs = deadline
+        .duration_since(Instant::now())
+        .as_secs()
+        .min(u64::from(WAIT_TIMEOUT_MAX_SECS)) as u32;
+    ensure!(start_timeout_secs > 0, "machine did not start in time");
+    let outcome = machine::wait(
+        app_name.clone(),
+        machine_id.clone(),
+        MachineState::Started,
+        Some(instance_id),
+        Some(start_timeout_secs),
+    )
+    .await?;
+    if matches!(outcome, WaitOutcome::TimedOut) {
+        bail!("machine did not start within {start_timeout_secs}s");
+    }
+    loop {
+        let machine = machine::get(app_name.clone(), machine_id.clone())
+            .await?
+            .with_context(|| format!("machine {machine_id} not found"))?;
+        let failing: Vec<_> = machine
+            .checks
+            .iter()
+            .flatten()
+            .filter(|check| !matches!(check.status, CheckState::Passing))
+            .map(|check| check.name.as_str())
+            .collect();
+        if failing.is_empty() {
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            bail!("checks not passing in time: {}", failing.join(", "));
+        }
+        wstd::task::sleep(Duration::from_secs(CHECKS_POLL_INTERVAL_SECS)).await;
+    }
+}
+
+/// Machines of the app grouped by what a single `update-image` call does with them.
+#[derive(Debug)]
+struct UpdatePlan {
+    update: Vec<Machine>,
+    unchanged: Vec<Machine>,
+    /// Left for the next call because of `MAX_MACHINES_PER_CALL`.
+    pending: Vec<Machine>,
+    /// Excluded by the canary limit.
+    held_back: Vec<Machine>,
+}
+
+/// Split the machines into those that should be updated and those already running the image.
+/// Machines are ordered by ID so that retries update them in the same order.
+/// Unless the strategy is `immediate`, at most `MAX_MACHINES_PER_CALL` machines are updated,
+/// the rest is returned as pending.
+fn plan_update(mut machines: Vec<Machine>, image: &str, strategy: Strategy) -> UpdatePlan {
+    machines.sort_by(|a, b| a.id.cmp(&b.id));
+    let (unchanged, mut outdated): (Vec<_>, Vec<_>) = machines
+        .into_iter()
+        .partition(|machine| machine.config.image == image);
+    // Machines already running the image count towards the canary, so that retries do not exceed it.
+    let held_back = match strategy {
+        Strategy::Canary(count) => {
+            let remaining = (count as usize).saturating_sub(unchanged.len());
+            outdated.split_off(remaining.min(outdated.len()))
+        }
+        Strategy::Rolling | Strategy::Immediate => Vec::new(),
+    };
+    let pending = match strategy {
+        Strategy::Rolling | Strategy::Canary(_) => {
+            outdated.split_off(MAX_MACHINES_PER_CALL.min(outdated.len()))
+        }
+        // Health checks of all machines share one deadline, see `update_image`.
+        Strategy::Immediate => Vec::new(),
+    };
+    UpdatePlan {
+        update: outdated,
+        unchanged,
+        pending,
+        held_back,
+    }
+}
+
+async fn update_image(
+    app_name: AppName,
+    image: String,
+    strategy: Strategy,
+) -> Result<DeployReport, anyhow::Error> {
+    let machines = machine::list(app_name.clone(), None).await?;
+    let plan = plan_update(machines, &image, strategy);
+    let ids = |machines: Vec<Machine>| machines.into_iter().map(|machine| machine.id).collect();
+    let mut report = DeployReport {
+        updated: Vec::new(),
+        unchanged: ids(plan.unchanged),
+        pending: ids(plan.pending),
+        held_back: ids(plan.held_back),
+        failure: None,
+    };
+    let mut to_wait = Vec::new();
+    let mut outdated = plan.update.into_iter();
+    while let Some(machine) = outdated.next() {
+        let result = match update_machine(&app_name, &machine, &image).await {
+            Ok(Some(instance_id)) if !matches!(strategy, Strategy::Immediate) => {
+                wait_healthy(&app_name, &machine.id, instance_id, healthy_deadline()).await
+            }
+            Ok(Some(instance_id)) => {
+                to_wait.push((machine.id.clone(), instance_id));
+                Ok(())
+            }
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(()) => report.updated.push(machine.id),
+            Err(err) => {
+                report.failure = Some(DeployFailure {
+                    machine_id: machine.id,
+                    error: err.to_string(),
+                });
+                report.pending.extend(outdated.map(|machine| machine.id));
+                return Ok(report);
+            }
+        }
+    }
+    let deadline = healthy_deadline();
+    for (machine_id, instance_id) in to_wait {
+        if let Err(err) = wait_healthy(&app_name, &machine_id, instance_id, deadline).await {
+            report.failure = Some(DeployFailure {
+                machine_id,
+                error: err.to_string(),
+            });
+            return Ok(report);
+        }
+    }
+    Ok(report)
+}
+
+impl deploy::Guest for crate::Component {
+    fn update_image(
+        app_name: String,
+        image: String,
+        strategy: Strategy,
+    ) -> Result<DeployReport, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            block_on(update_image(app_name, image, strategy))
+        })()
+        .map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{plan_update, update_options, with_image};
+    use crate::generated::exports::obelisk_flyio::activity_fly_http::{
+        deploy::Strategy,
+        machines::{Machine, MachineState},
+    };
+    use crate::machine::test_util::test_machine;
+    use serde_json::json;
+
+    fn machine(id: &str, image: &str) -> Machine {
+        test_machine(id, image, &[])
+    }
+
+    fn ids(machines: &[Machine]) -> Vec<&str> {
+        machines.iter().map(|machine| machine.id.as_str()).collect()
+    }
+
+    #[test]
+    fn plan_update_should_sort_and_skip_up_to_date_machines() {
+        let machines = vec![machine("c", "v1"), machine("a", "v1"), machine("b", "v2")];
+        let plan = plan_update(machines, "v2", Strategy::Rolling);
+        assert_eq!(vec!["a", "c"], ids(&plan.update));
+        assert_eq!(vec!["b"], ids(&plan.unchanged));
+        assert!(plan.pending.is_empty());
+        assert!(plan.held_back.is_empty());
+    }
+
+    #[test]
+    fn plan_update_canary_should_limit_updated_machines() {
+        let machines = vec![machine("c", "v1"), machine("a", "v1"), machine("b", "v1")];
+        let plan = plan_update(machines, "v2", Strategy::Canary(1));
+        assert_eq!(vec!["a"], ids(&plan.update));
+        assert!(plan.unchanged.is_empty());
+        assert!(plan.pending.is_empty());
+        assert_eq!(vec!["b", "c"], ids(&plan.held_back));
+    }
+
+    #[test]
+    fn plan_update_canary_should_count_updated_machines_on_retry() {
+        let machines = vec![machine("c", "v1"), machine("a", "v2"), machine("b", "v1")];
+        let plan = plan_update(machines.clone(), "v2", Strategy::Canary(1));
+        assert!(plan.update.is_empty());
+        assert_eq!(vec!["b", "c"], ids(&plan.held_back));
+        let plan = plan_update(machines, "v2", Strategy::Canary(2));
+        assert_eq!(vec!["b"], ids(&plan.update));
+        assert_eq!(vec!["c"], ids(&plan.held_back));
+    }
+
+    #[test]
+    fn plan_update_should_limit_machines_per_call() {
+        let machines = vec![machine("c", "v1"), machine("a", "v1"), machine("b", "v1")];
+        let plan = plan_update(machines.clone(), "v2", Strategy::Rolling);
+        assert_eq!(vec!["a", "b"], ids(&plan.update));
+        assert_eq!(vec!["c"], ids(&plan.pending));
+        let plan = plan_update(machines.clone(), "v2", Strategy::Canary(5));
+        assert_eq!(vec!["a", "b"], ids(&plan.update));
+        assert_eq!(vec!["c"], ids(&plan.pending));
+        assert!(plan.held_back.is_empty());
+        let plan = plan_update(machines, "v2", Strategy::Immediate);
+        assert_eq!(vec!["a", "b", "c"], ids(&plan.update));
+        assert!(plan.pending.is_empty());
+    }
+
+    #[test]
+    fn update_options_should_skip_launch_unless_started() {
+        assert!(update_options(MachineState::Started).is_none());
+        for state in [MachineState::Stopped, MachineState::Suspended] {
+            assert_eq!(Some(true), update_options(state).unwrap().skip_launch);
+        }
+    }
+
+    #[test]
+    fn with_image_should_keep_unmodeled_fields() {
+        let config = json!({"image": "v1", "processes": [{"cmd": ["serve"]}], "dns": {"skip_registration": true}});
+        assert_eq!(
+            json!({"image": "v2", "processes": [{"cmd": ["serve"]}], "dns": {"skip_registration": true}}),
+            with_image(config, "v2").unwrap()
+        );
+    }
+}
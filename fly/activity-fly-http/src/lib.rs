@@ -1,7 +1,9 @@
 mod app;
+mod deploy;
 mod ips;
 mod machine;
 mod secret;
+mod serde_util;
 mod volume;
 mod wstd_util;
 mod generated {
@@ -45,12 +47,33 @@ impl<T> SafeUrlPart<T> {
         })
     }
 }
+impl<T> Clone for SafeUrlPart<T> {
+    fn clone(&self) -> Self {
+        SafeUrlPart {
+            value: self.value.clone(),
+            _phantom_data: PhantomData,
+        }
+    }
+}
 impl<T> AsRef<str> for SafeUrlPart<T> {
     fn as_ref(&self) -> &str {
         &self.value
     }
 }
 
+/// Percent-encode a query parameter value, keeping only the unreserved characters of RFC 3986.
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
 struct AppMarker;
 type AppName = SafeUrlPart<AppMarker>;
 struct OrgMarker;
@@ -61,3 +84,9 @@ struct VolumeIdMarker;
 type VolumeId = SafeUrlPart<VolumeIdMarker>;
 struct MachineIdMarker;
 type MachineId = SafeUrlPart<MachineIdMarker>;
+struct InstanceIdMarker;
+type InstanceId = SafeUrlPart<InstanceIdMarker>;
+struct LeaseNonceMarker;
+type LeaseNonce = SafeUrlPart<LeaseNonceMarker>;
+struct MetadataKeyMarker;
+type MetadataKey = SafeUrlPart<MetadataKeyMarker>;
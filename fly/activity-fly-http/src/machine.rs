@@ -1,20 +1,29 @@
 use crate::generated::exports::obelisk_flyio::activity_fly_http::machines::{
-    ExecResponse, Guest, Machine, MachineConfig,
+    CloneOverrides, ConfigDiff, CpuKind, CreateOptions, EnsureOutcome, EnsureResult, ExecOptions,
+    ExecResponse, ExitEvent, File, Guest, GuestConfig, GuestDiff, GuestPreset, ImageDiff,
+    JobResult, Lease, ListFilter, Machine, MachineConfig, MachineEvent, MachineState,
+    MachineVersion, MountsDiff, Process, ScaleReport, ServicesDiff, StopConfig, TransitionResult,
+    WaitOutcome,
 };
 use crate::generated::obelisk_flyio::activity_fly_http::regions::Region;
 use crate::wstd_util::JsonRequest as _;
-use crate::{API_BASE_URL, AppName, Component, MachineId, request_with_api_token};
+use crate::{
+    API_BASE_URL, AppName, Component, InstanceId, LeaseNonce, MachineId, MetadataKey,
+    percent_encode, request_with_api_token,
+};
 use anyhow::{Context, anyhow, bail, ensure};
 use ser::{
-    ExecResponseSer, MachineCreateRequestSer, MachineCreateResponseSer, MachineUpdateRequestSer,
-    ResponseErrorSer,
+    ExecRequestSer, ExecResponseSer, LeaseRequestSer, LeaseResponseSer, MachineCreateRequestSer,
+    MachineEventSer, MachineUpdateRequestSer, MachineVersionSer, ResponseErrorSer,
 };
-use wstd::http::{Body, Client, Method, StatusCode};
+use std::collections::BTreeMap;
+use wstd::http::{Body, Client, Method, Request, StatusCode, request};
 use wstd::runtime::block_on;
+use wstd::time::Instant;
 
 pub(crate) mod ser {
     use crate::generated::exports::obelisk_flyio::activity_fly_http::machines::{
-        ExecResponse, MachineConfig,
+        CreateOptions, ExecResponse, ExitEvent, Lease, MachineConfig, MachineEvent, MachineVersion,
     };
     use crate::generated::obelisk_flyio::activity_fly_http::regions::Region;
     use serde::{Deserialize, Serialize};
@@ -22,19 +31,42 @@ pub(crate) mod ser {
     #[derive(Serialize, Debug)]
     pub(crate) struct MachineCreateRequestSer {
         pub(crate) name: String,
-        pub(crate) config: MachineConfig,
+        /// Raw JSON, so that fields not modeled by `MachineConfig` are preserved.
+        pub(crate) config: serde_json::Value,
         pub(crate) region: Option<Region>,
+        #[serde(flatten)]
+        pub(crate) options: CreateOptionsSer,
     }
 
     #[derive(Serialize, Debug)]
     pub(crate) struct MachineUpdateRequestSer {
-        pub(crate) config: MachineConfig,
+        /// Raw JSON, so that fields not modeled by `MachineConfig` are preserved.
+        pub(crate) config: serde_json::Value,
         pub(crate) region: Option<Region>,
+        #[serde(flatten)]
+        pub(crate) options: CreateOptionsSer,
+    }
+
+    #[derive(Serialize, Debug, Default)]
+    pub(crate) struct CreateOptionsSer {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        skip_launch: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        skip_service_registration: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        lease_ttl: Option<u32>,
     }
 
-    #[derive(Deserialize)]
-    pub(crate) struct MachineCreateResponseSer {
-        pub(crate) id: String,
+    impl From<Option<CreateOptions>> for CreateOptionsSer {
+        fn from(value: Option<CreateOptions>) -> Self {
+            value
+                .map(|options| CreateOptionsSer {
+                    skip_launch: options.skip_launch,
+                    skip_service_registration: options.skip_service_registration,
+                    lease_ttl: options.lease_ttl_secs,
+                })
+                .unwrap_or_default()
+        }
     }
 
     #[derive(Deserialize, Debug)]
@@ -56,6 +88,77 @@ pub(crate) mod ser {
         }
     }
 
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct MachineEventSer {
+        id: String,
+        #[serde(rename = "type")]
+        event_type: String,
+        status: String,
+        source: String,
+        timestamp: u64,
+        request: Option<MachineEventRequestSer>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct MachineEventRequestSer {
+        exit_event: Option<ExitEvent>,
+    }
+
+    impl From<MachineEventSer> for MachineEvent {
+        fn from(value: MachineEventSer) -> Self {
+            MachineEvent {
+                id: value.id,
+                type_: value.event_type,
+                status: value.status,
+                source: value.source,
+                timestamp: value.timestamp,
+                exit_event: value.request.and_then(|request| request.exit_event),
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub(crate) struct MachineVersionSer {
+        version: String,
+        user_config: MachineConfig,
+    }
+
+    impl From<MachineVersionSer> for MachineVersion {
+        fn from(value: MachineVersionSer) -> Self {
+            MachineVersion {
+                created_at_ms: ulid::Ulid::from_string(&value.version)
+                    .ok()
+                    .map(|ulid| ulid.timestamp_ms()),
+                version: value.version,
+                config: value.user_config,
+            }
+        }
+    }
+
+    #[derive(Serialize, Debug)]
+    pub(crate) struct LeaseRequestSer {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub(crate) ttl: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub(crate) description: Option<String>,
+    }
+
+    #[derive(Deserialize, Debug)]
+    pub(crate) struct LeaseResponseSer {
+        pub(crate) data: Lease,
+    }
+
+    #[derive(Serialize, Debug)]
+    pub(crate) struct ExecRequestSer {
+        pub(crate) command: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub(crate) stdin: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub(crate) timeout: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub(crate) container: Option<String>,
+    }
+
     #[derive(Debug, Deserialize)]
     pub(crate) struct ExecResponseSer {
         exit_code: Option<i32>,
@@ -75,8 +178,57 @@ pub(crate) mod ser {
     }
 }
 
-async fn list(app_name: AppName) -> Result<Vec<Machine>, anyhow::Error> {
-    let url = format!("{API_BASE_URL}/apps/{app_name}/machines");
+/// Build the query string of the `list` endpoint.
+fn list_query(filter: &ListFilter) -> Result<String, anyhow::Error> {
+    let mut params = Vec::new();
+    for (key, value) in &filter.metadata {
+        let key = MetadataKey::new(key.clone())?;
+        params.push(format!("metadata.{key}={}", percent_encode(value)));
+    }
+    if let Some(region) = filter.region {
+        params.push(format!("region={}", query_value(&region)?));
+    }
+    if let Some(state) = filter.state {
+        params.push(format!("state={}", query_value(&state)?));
+    }
+    Ok(if params.is_empty() {
+        String::new()
+    } else {
+        format!("?{}", params.join("&"))
+    })
+}
+
+/// Serialize an enum the same way as in a JSON body.
+fn query_value<T: serde::Serialize>(value: &T) -> Result<String, anyhow::Error> {
+    match serde_json::to_value(value)? {
+        serde_json::Value::String(value) => Ok(value),
+        other => bail!("cannot use {other} as a query parameter"),
+    }
+}
+
+/// Metadata key set by `cordon` and removed by `uncordon`.
+const CORDONED_METADATA_KEY: &str = "cordoned";
+
+/// The Machines API does not report the cordon state, derive it from the metadata set by `cordon`.
+fn with_cordon_state(mut machine: Machine) -> Machine {
+    machine.cordoned = machine
+        .config
+        .metadata
+        .iter()
+        .flatten()
+        .any(|(key, value)| key == CORDONED_METADATA_KEY && value == "true");
+    machine
+}
+
+pub(crate) async fn list(
+    app_name: AppName,
+    filter: Option<ListFilter>,
+) -> Result<Vec<Machine>, anyhow::Error> {
+    let query = match filter {
+        Some(filter) => list_query(&filter)?,
+        None => String::new(),
+    };
+    let url = format!("{API_BASE_URL}/apps/{app_name}/machines{query}");
     let request = request_with_api_token()?
         .method(Method::GET)
         .uri(url)
@@ -89,14 +241,30 @@ async fn list(app_name: AppName) -> Result<Vec<Machine>, anyhow::Error> {
     if resp_status.is_success() {
         let response: Vec<Machine> = serde_json::from_str(response)
             .inspect_err(|_| eprintln!("cannot deserialize: {response}"))?;
-        Ok(response)
+        Ok(response.into_iter().map(with_cordon_state).collect())
     } else {
         eprintln!("Got error status {resp_status}");
         Err(anyhow!("failed with status {resp_status}: {response}"))
     }
 }
 
-async fn get(app_name: AppName, machine_id: MachineId) -> Result<Option<Machine>, anyhow::Error> {
+pub(crate) async fn get(
+    app_name: AppName,
+    machine_id: MachineId,
+) -> Result<Option<Machine>, anyhow::Error> {
+    let Some(machine) = get_raw(app_name, machine_id).await? else {
+        return Ok(None);
+    };
+    let machine = serde_json::from_value(machine.clone())
+        .inspect_err(|_| eprintln!("cannot deserialize: {machine}"))?;
+    Ok(Some(with_cordon_state(machine)))
+}
+
+/// Get the machine as raw JSON, including fields not modeled by `Machine`.
+async fn get_raw(
+    app_name: AppName,
+    machine_id: MachineId,
+) -> Result<Option<serde_json::Value>, anyhow::Error> {
     let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}");
     let request = request_with_api_token()?
         .method(Method::GET)
@@ -108,7 +276,7 @@ async fn get(app_name: AppName, machine_id: MachineId) -> Result<Option<Machine>
     let response = response.str_contents().await?;
 
     if resp_status.is_success() {
-        let response: Machine = serde_json::from_str(response)
+        let response: serde_json::Value = serde_json::from_str(response)
             .inspect_err(|_| eprintln!("cannot deserialize: {response}"))?;
         Ok(Some(response))
     } else if resp_status == StatusCode::NOT_FOUND {
@@ -119,17 +287,336 @@ async fn get(app_name: AppName, machine_id: MachineId) -> Result<Option<Machine>
     }
 }
 
+/// CPU kind, CPU count and default memory in MB of a guest preset.
+fn guest_preset(preset: GuestPreset) -> (CpuKind, u64, u64) {
+    match preset {
+        GuestPreset::SharedCpu1x => (CpuKind::Shared, 1, 256),
+        GuestPreset::SharedCpu2x => (CpuKind::Shared, 2, 512),
+        GuestPreset::SharedCpu4x => (CpuKind::Shared, 4, 1024),
+        GuestPreset::SharedCpu8x => (CpuKind::Shared, 8, 2048),
+        GuestPreset::Performance1x => (CpuKind::Performance, 1, 2048),
+        GuestPreset::Performance2x => (CpuKind::Performance, 2, 4096),
+        GuestPreset::Performance4x => (CpuKind::Performance, 4, 8192),
+        GuestPreset::Performance8x => (CpuKind::Performance, 8, 16384),
+        GuestPreset::Performance16x => (CpuKind::Performance, 16, 32768),
+    }
+}
+
+/// Replace the guest preset with the values it stands for, as the API does not know presets.
+fn resolve_guest_preset(mut machine_config: MachineConfig) -> Result<MachineConfig, anyhow::Error> {
+    if let Some(guest) = machine_config.guest.as_mut() {
+        resolve_guest(guest)?;
+    }
+    Ok(machine_config)
+}
+
+fn resolve_guest(guest: &mut GuestConfig) -> Result<(), anyhow::Error> {
+    if let Some(preset) = guest.preset.take() {
+        ensure!(
+            guest.cpu_kind.is_none() && guest.cpus.is_none(),
+            "`cpu-kind` and `cpus` cannot be combined with a guest preset"
+        );
+        let (cpu_kind, cpus, memory_mb) = guest_preset(preset);
+        guest.cpu_kind = Some(cpu_kind);
+        guest.cpus = Some(cpus);
+        guest.memory_mb.get_or_insert(memory_mb);
+    }
+    Ok(())
+}
+
+/// Check the CPU count and memory against the limits of Fly guests.
+/// Missing values are replaced by the API defaults: one shared CPU with 256MB.
+fn validate_guest(guest: &GuestConfig) -> Result<(), anyhow::Error> {
+    let cpu_kind = guest.cpu_kind.unwrap_or(CpuKind::Shared);
+    let cpus = guest.cpus.unwrap_or(1);
+    let (kind, max_cpus, min_mb_per_cpu, max_mb_per_cpu) = match cpu_kind {
+        CpuKind::Shared => ("shared", 8, 256, 2048),
+        CpuKind::Performance => ("performance", 16, 2048, 8192),
+    };
+    ensure!(
+        cpus.is_power_of_two() && cpus <= max_cpus,
+        "{kind} guests support 1, 2, 4, ... {max_cpus} CPUs, got {cpus}"
+    );
+    if let Some(memory_mb) = guest.memory_mb {
+        ensure!(
+            memory_mb % 256 == 0,
+            "memory must be a multiple of 256MB, got {memory_mb}MB"
+        );
+        let (min_mb, max_mb) = (min_mb_per_cpu * cpus, max_mb_per_cpu * cpus);
+        ensure!(
+            (min_mb..=max_mb).contains(&memory_mb),
+            "{kind} guests with {cpus} CPUs need between {min_mb}MB and {max_mb}MB of memory, got {memory_mb}MB"
+        );
+    }
+    Ok(())
+}
+
+fn validate_file(file: &File) -> Result<(), anyhow::Error> {
+    ensure!(
+        file.guest_path.starts_with('/'),
+        "guest path of a file must be absolute, got `{}`",
+        file.guest_path
+    );
+    ensure!(
+        file.raw_value.is_some() != file.secret_name.is_some(),
+        "exactly one of `raw-value` and `secret-name` must be set for file `{}`",
+        file.guest_path
+    );
+    Ok(())
+}
+
+/// Get the raw configuration of the machine, to be sent back using `update_raw` or `create_machine_raw`.
+pub(crate) async fn get_config_raw(
+    app_name: AppName,
+    machine_id: MachineId,
+) -> Result<serde_json::Value, anyhow::Error> {
+    let mut machine = get_raw(app_name, machine_id.clone())
+        .await?
+        .with_context(|| format!("machine {machine_id} not found"))?;
+    match machine["config"].take() {
+        config @ serde_json::Value::Object(_) => Ok(config),
+        other => bail!("machine {machine_id} has no configuration: {other}"),
+    }
+}
+
+/// Reject configurations that the Machines API would refuse or misinterpret.
+fn validate_config(machine_config: &MachineConfig) -> Result<(), anyhow::Error> {
+    if let Some(guest) = &machine_config.guest {
+        validate_guest(guest)?;
+    }
+    for file in machine_config.files.iter().flatten() {
+        validate_file(file)?;
+    }
+    let containers = machine_config.containers.as_deref().unwrap_or_default();
+    for (idx, container) in containers.iter().enumerate() {
+        ensure!(
+            !containers[..idx]
+                .iter()
+                .any(|other| other.name == container.name),
+            "container name `{}` is not unique",
+            container.name
+        );
+        for file in container.files.iter().flatten() {
+            validate_file(file)?;
+        }
+        for dependency in container.depends_on.iter().flatten() {
+            ensure!(
+                dependency.name != container.name
+                    && containers.iter().any(|other| other.name == dependency.name),
+                "container `{}` depends on unknown container `{}`",
+                container.name,
+                dependency.name
+            );
+        }
+    }
+    for service in machine_config.services.iter().flatten() {
+        for port in &service.ports {
+            match (port.port, port.start_port, port.end_port) {
+                (Some(_), None, None) => {}
+                (None, Some(start_port), Some(end_port)) => ensure!(
+                    start_port <= end_port,
+                    "invalid port range {start_port}-{end_port}"
+                ),
+                _ => bail!(
+                    "either `port` or both `start-port` and `end-port` must be set for internal port {}",
+                    service.internal_port
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check if the value is a Go zero value, which the API omits from its responses.
+fn is_zero_value(value: &serde_json::Value) -> bool {
+    use serde_json::Value;
+    match value {
+        Value::Null => true,
+        Value::Bool(value) => !value,
+        Value::Number(value) => value.as_f64() == Some(0.0),
+        Value::String(value) => value.is_empty(),
+        Value::Array(values) => values.is_empty(),
+        Value::Object(values) => values.values().all(is_zero_value),
+    }
+}
+
+/// Check that every field set in `desired` has the same value in `live`.
+/// Missing and `null` fields of `desired` are ignored, as the API fills them with defaults.
+/// Zero values of `desired` match missing fields of `live`.
+fn json_subset(desired: &serde_json::Value, live: &serde_json::Value) -> bool {
+    use serde_json::Value;
+    match (desired, live) {
+        (Value::Null, _) => true,
+        (Value::Object(desired), Value::Object(live)) => desired
+            .iter()
+            .all(|(key, desired)| json_subset(desired, live.get(key).unwrap_or(&Value::Null))),
+        (desired, Value::Null) => is_zero_value(desired),
+        (Value::Array(desired), Value::Array(live)) => {
+            desired.len() == live.len()
+                && desired
+                    .iter()
+                    .zip(live)
+                    .all(|(desired, live)| json_subset(desired, live))
+        }
+        (desired, live) => desired == live,
+    }
+}
+
+/// Return the live and the desired value if the desired value is set and differs.
+fn diff_field<T: serde::Serialize + Clone>(
+    desired: &Option<T>,
+    live: &Option<T>,
+) -> Result<Option<(Option<T>, T)>, anyhow::Error> {
+    let Some(desired) = desired else {
+        return Ok(None);
+    };
+    if json_subset(
+        &serde_json::to_value(desired)?,
+        &serde_json::to_value(live)?,
+    ) {
+        Ok(None)
+    } else {
+        Ok(Some((live.clone(), desired.clone())))
+    }
+}
+
+/// Compare the desired configuration with the one reported by the API.
+fn diff_config(desired: &MachineConfig, live: &MachineConfig) -> Result<ConfigDiff, anyhow::Error> {
+    let mut diff = ConfigDiff {
+        changed: false,
+        image: None,
+        env_added: Vec::new(),
+        env_removed: Vec::new(),
+        env_changed: Vec::new(),
+        guest: diff_field(&desired.guest, &live.guest)?
+            .map(|(live, desired)| GuestDiff { live, desired }),
+        mounts: diff_field(&desired.mounts, &live.mounts)?.map(|(live, desired)| MountsDiff {
+            live: live.unwrap_or_default(),
+            desired,
+        }),
+        services: diff_field(&desired.services, &live.services)?.map(|(live, desired)| {
+            ServicesDiff {
+                live: live.unwrap_or_default(),
+                desired,
+            }
+        }),
+        other_fields: Vec::new(),
+    };
+    if desired.image != live.image {
+        diff.image = Some(ImageDiff {
+            live: live.image.clone(),
+            desired: desired.image.clone(),
+        });
+    }
+    // Unlike other maps, `env` is authoritative: a missing key means the variable should be removed.
+    if let Some(desired_env) = &desired.env {
+        let desired_env: BTreeMap<_, _> = desired_env.iter().map(|(k, v)| (k, v)).collect();
+        let live_env: BTreeMap<_, _> = live.env.iter().flatten().map(|(k, v)| (k, v)).collect();
+        for (key, value) in &desired_env {
+            match live_env.get(key) {
+                None => diff.env_added.push(key.to_string()),
+                Some(live_value) if live_value != value => diff.env_changed.push(key.to_string()),
+                Some(_) => {}
+            }
+        }
+        diff.env_removed = live_env
+            .keys()
+            .filter(|key| !desired_env.contains_key(*key))
+            .map(|key| key.to_string())
+            .collect();
+    }
+    let serde_json::Value::Object(desired) = serde_json::to_value(desired)? else {
+        bail!("machine config must be serialized as an object");
+    };
+    let live = serde_json::to_value(live)?;
+    for (key, desired) in desired {
+        if !matches!(
+            key.as_str(),
+            "image" | "env" | "guest" | "mounts" | "services"
+        ) && !json_subset(&desired, live.get(&key).unwrap_or(&serde_json::Value::Null))
+        {
+            diff.other_fields.push(key.replace('_', "-"));
+        }
+    }
+    diff.changed = diff.image.is_some()
+        || !diff.env_added.is_empty()
+        || !diff.env_removed.is_empty()
+        || !diff.env_changed.is_empty()
+        || diff.guest.is_some()
+        || diff.mounts.is_some()
+        || diff.services.is_some()
+        || !diff.other_fields.is_empty();
+    Ok(diff)
+}
+
+fn config_differs(desired: &MachineConfig, live: &MachineConfig) -> Result<bool, anyhow::Error> {
+    Ok(diff_config(desired, live)?.changed)
+}
+
+async fn plan(
+    app_name: AppName,
+    machine_id: MachineId,
+    desired: MachineConfig,
+) -> Result<ConfigDiff, anyhow::Error> {
+    let desired = resolve_guest_preset(desired)?;
+    let live = get(app_name, machine_id.clone())
+        .await?
+        .with_context(|| format!("machine {machine_id} not found"))?;
+    diff_config(&desired, &live.config)
+}
+
+/// Result of the create endpoint.
+enum Creation {
+    Created(Machine),
+    /// A machine with the same name already exists.
+    AlreadyExists(Machine),
+}
+
 async fn create(
     app_name: AppName,
     machine_name: String,
     machine_config: MachineConfig,
     region: Option<Region>,
-) -> Result<String, anyhow::Error> {
+    options: Option<CreateOptions>,
+) -> Result<Machine, anyhow::Error> {
+    match create_machine(app_name, machine_name, machine_config, region, options).await? {
+        Creation::Created(machine) | Creation::AlreadyExists(machine) => Ok(machine),
+    }
+}
+
+async fn create_machine(
+    app_name: AppName,
+    machine_name: String,
+    machine_config: MachineConfig,
+    region: Option<Region>,
+    options: Option<CreateOptions>,
+) -> Result<Creation, anyhow::Error> {
+    let machine_config = resolve_guest_preset(machine_config)?;
+    validate_config(&machine_config)?;
+    create_machine_raw(
+        app_name,
+        machine_name,
+        serde_json::to_value(machine_config)?,
+        region,
+        options,
+    )
+    .await
+}
+
+/// Create a machine with a configuration as returned by the API, keeping fields unknown to `MachineConfig`.
+async fn create_machine_raw(
+    app_name: AppName,
+    machine_name: String,
+    machine_config: serde_json::Value,
+    region: Option<Region>,
+    options: Option<CreateOptions>,
+) -> Result<Creation, anyhow::Error> {
     {
         let request_payload = MachineCreateRequestSer {
             name: machine_name,
             config: machine_config,
             region,
+            options: options.into(),
         };
         let url = format!("{API_BASE_URL}/apps/{app_name}/machines");
         let request = request_with_api_token()?
@@ -143,9 +630,9 @@ async fn create(
         let response = response.str_contents().await?;
 
         if resp_status.is_success() {
-            let resp: MachineCreateResponseSer = serde_json::from_str(response)
+            let machine: Machine = serde_json::from_str(response)
                 .with_context(|| format!("Deserialization of response failed: `{response}`"))?;
-            return Ok(resp.id);
+            return Ok(Creation::Created(with_cordon_state(machine)));
         }
         eprintln!("Got error status {resp_status}");
         if resp_status == StatusCode::CONFLICT {
@@ -154,26 +641,56 @@ async fn create(
             let machine_id = error.get_machine_id_on_creation_conflict().with_context(
                 || "machine id cannot be parsed from 409 error response: `{error:?}`",
             )?;
-            Ok(machine_id.to_string())
+            let machine_id = MachineId::new(machine_id.to_string())?;
+            let machine = get(app_name, machine_id.clone()).await?.with_context(|| {
+                format!("machine {machine_id} disappeared after creation conflict")
+            })?;
+            Ok(Creation::AlreadyExists(machine))
         } else {
             Err(anyhow!("{resp_status} - {response}"))
         }
     }
 }
 
-async fn update(
+/// Update the machine, return it with the instance ID of its new version.
+pub(crate) async fn update(
     app_name: AppName,
     machine_id: MachineId,
     machine_config: MachineConfig,
     region: Option<Region>,
-) -> Result<(), anyhow::Error> {
+    lease_nonce: Option<LeaseNonce>,
+    options: Option<CreateOptions>,
+) -> Result<Machine, anyhow::Error> {
+    let machine_config = resolve_guest_preset(machine_config)?;
+    validate_config(&machine_config)?;
+    update_raw(
+        app_name,
+        machine_id,
+        serde_json::to_value(machine_config)?,
+        region,
+        lease_nonce,
+        options,
+    )
+    .await
+}
+
+/// Update the machine with a configuration as returned by the API, keeping fields unknown to `MachineConfig`.
+pub(crate) async fn update_raw(
+    app_name: AppName,
+    machine_id: MachineId,
+    machine_config: serde_json::Value,
+    region: Option<Region>,
+    lease_nonce: Option<LeaseNonce>,
+    options: Option<CreateOptions>,
+) -> Result<Machine, anyhow::Error> {
     {
         let request_payload = MachineUpdateRequestSer {
             config: machine_config,
             region,
+            options: options.into(),
         };
         let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}");
-        let request = request_with_api_token()?
+        let request = request_with_lease_nonce(lease_nonce.as_ref())?
             .method(Method::POST)
             .uri(url)
             .json(&request_payload)?;
@@ -184,32 +701,372 @@ async fn update(
         let response = response.str_contents().await?;
 
         if resp_status.is_success() {
-            let resp: MachineCreateResponseSer = serde_json::from_str(response)
+            let machine: Machine = serde_json::from_str(response)
                 .with_context(|| format!("Deserialization of response failed: `{response}`"))?;
             ensure!(
-                resp.id == machine_id.as_ref(),
+                machine.id == machine_id.as_ref(),
                 "unexpected id returned, expected {machine_id} got {id}",
-                id = resp.id
+                id = machine.id
             );
-            return Ok(());
+            return Ok(with_cordon_state(machine));
         }
         bail!("{resp_status} - {response}")
     }
 }
 
+async fn ensure(
+    app_name: AppName,
+    machine_name: String,
+    machine_config: MachineConfig,
+    region: Option<Region>,
+) -> Result<EnsureResult, anyhow::Error> {
+    let machine_config = resolve_guest_preset(machine_config)?;
+    let creation = create_machine(
+        app_name.clone(),
+        machine_name,
+        machine_config.clone(),
+        region,
+        None,
+    )
+    .await?;
+    let live = match creation {
+        Creation::Created(machine) => {
+            return Ok(EnsureResult {
+                machine_id: machine.id,
+                outcome: EnsureOutcome::Created,
+            });
+        }
+        Creation::AlreadyExists(machine) => machine,
+    };
+    let machine_id = MachineId::new(live.id)?;
+    let outcome = if config_differs(&machine_config, &live.config)? {
+        update(
+            app_name,
+            machine_id.clone(),
+            machine_config,
+            region,
+            None,
+            None,
+        )
+        .await?;
+        EnsureOutcome::Updated
+    } else {
+        EnsureOutcome::Unchanged
+    };
+    Ok(EnsureResult {
+        machine_id: machine_id.to_string(),
+        outcome,
+    })
+}
+
+/// Apply `overrides` to the raw configuration of the source machine.
+/// User metadata is dropped, so that the clone is not matched by metadata selectors of the source.
+fn clone_config(
+    mut machine_config: serde_json::Value,
+    overrides: Option<CloneOverrides>,
+) -> Result<serde_json::Value, anyhow::Error> {
+    use serde_json::Value;
+    let overrides = overrides.unwrap_or(CloneOverrides {
+        image: None,
+        env: None,
+        guest: None,
+        volume: None,
+    });
+    let config = machine_config
+        .as_object_mut()
+        .context("machine configuration must be an object")?;
+    if let Some(image) = overrides.image {
+        config.insert("image".to_string(), Value::String(image));
+    }
+    if let Some(env) = overrides.env {
+        let env: BTreeMap<_, _> = env.into_iter().collect();
+        config.insert("env".to_string(), serde_json::to_value(env)?);
+    }
+    if let Some(mut guest) = overrides.guest {
+        resolve_guest(&mut guest)?;
+        validate_guest(&guest)?;
+        config.insert("guest".to_string(), serde_json::to_value(guest)?);
+    }
+    let mounts = config
+        .get_mut("mounts")
+        .and_then(Value::as_array_mut)
+        .filter(|mounts| !mounts.is_empty());
+    match (mounts, overrides.volume) {
+        (Some(mounts), Some(volume)) => {
+            ensure!(
+                mounts.len() == 1,
+                "cannot replace the volume of a machine with {} mounts",
+                mounts.len()
+            );
+            mounts[0]["volume"] = Value::String(volume);
+        }
+        (Some(_), None) => {
+            bail!("cannot clone a machine with mounted volumes without a target volume")
+        }
+        (None, Some(_)) => bail!("the source machine does not mount a volume"),
+        (None, None) => {}
+    }
+    // Keys starting with `fly_` are managed by the platform, e.g. `fly_platform_version`.
+    if let Some(Value::Object(metadata)) = config.get_mut("metadata") {
+        metadata.retain(|key, _| key.starts_with("fly_"));
+    }
+    Ok(machine_config)
+}
+
+async fn clone(
+    app_name: AppName,
+    source_id: MachineId,
+    new_name: String,
+    region: Region,
+    overrides: Option<CloneOverrides>,
+) -> Result<Machine, anyhow::Error> {
+    let source_config = get_config_raw(app_name.clone(), source_id).await?;
+    let machine_config = clone_config(source_config, overrides)?;
+    match create_machine_raw(app_name, new_name, machine_config, Some(region), None).await? {
+        Creation::Created(machine) | Creation::AlreadyExists(machine) => Ok(machine),
+    }
+}
+
+/// Changes needed to reach the desired machine count in a single region.
+#[derive(Debug)]
+struct ScalePlan {
+    create: Vec<String>,
+    destroy: Vec<Machine>,
+    keep: Vec<Machine>,
+}
+
+/// Prefix of machine names managed by `scale`, e.g. `role-web-ams-` for the `role=web` selector.
+fn scale_name_prefix(
+    selector: &BTreeMap<String, String>,
+    region: Region,
+) -> Result<String, anyhow::Error> {
+    let mut parts: Vec<_> = selector
+        .iter()
+        .map(|(key, value)| format!("{key}-{value}"))
+        .collect();
+    parts.push(query_value(&region)?);
+    Ok(format!("{}-", parts.join("-")))
+}
+
+/// Destroying a machine takes up to `WAIT_TIMEOUT_MAX_SECS` for it to stop,
+/// keep `scale` well within the 900s lock expiry configured for this activity.
+const MAX_SCALE_CHANGES_PER_CALL: usize = 10;
+
+/// Trim the plans to at most `MAX_SCALE_CHANGES_PER_CALL` creations and destructions in total.
+/// Return the names of machines left to create and the machines left to destroy.
+fn limit_scale_plans(plans: &mut [(Region, ScalePlan)]) -> (Vec<String>, Vec<Machine>) {
+    let mut budget = MAX_SCALE_CHANGES_PER_CALL;
+    let mut pending_create = Vec::new();
+    let mut pending_destroy = Vec::new();
+    for (_, plan) in plans.iter_mut() {
+        let count = budget.min(plan.create.len());
+        pending_create.extend(plan.create.split_off(count));
+        budget -= count;
+        let count = budget.min(plan.destroy.len());
+        pending_destroy.extend(plan.destroy.split_off(count));
+        budget -= count;
+    }
+    (pending_create, pending_destroy)
+}
+
+/// Check that a machine found by name was created by `scale` for the `selector` in `region`.
+fn matches_selector(
+    machine: &Machine,
+    selector: &BTreeMap<String, String>,
+    region: Region,
+) -> bool {
+    let metadata: BTreeMap<_, _> = machine
+        .config
+        .metadata
+        .iter()
+        .flatten()
+        .map(|(key, value)| (key, value))
+        .collect();
+    machine.region == region
+        && selector
+            .iter()
+            .all(|(key, value)| metadata.get(key) == Some(&value))
+}
+
+/// Stop the machine gracefully, then delete it without force.
+async fn stop_and_delete(app_name: &AppName, machine: &Machine) -> Result<(), anyhow::Error> {
+    let machine_id = MachineId::new(machine.id.clone())?;
+    if !matches!(machine.state, MachineState::Suspended) {
+        transition(
+            app_name.clone(),
+            machine_id.clone(),
+            Transition::Stop,
+            stop(app_name.clone(), machine_id.clone(), None, None),
+        )
+        .await?;
+        let outcome = wait(
+            app_name.clone(),
+            machine_id.clone(),
+            MachineState::Stopped,
+            Some(InstanceId::new(machine.instance_id.clone())?),
+            Some(WAIT_TIMEOUT_MAX_SECS),
+        )
+        .await?;
+        if matches!(outcome, WaitOutcome::TimedOut) {
+            bail!("machine {machine_id} did not stop within {WAIT_TIMEOUT_MAX_SECS}s");
+        }
+    }
+    delete(app_name.clone(), machine_id, false, None).await
+}
+
+/// Machines with a name managed by `scale` are kept first, ordered by their index.
+/// Extra machines are taken from the end of that order.
+fn plan_scale(prefix: &str, count: u32, mut machines: Vec<Machine>) -> ScalePlan {
+    let index = |machine: &Machine| {
+        machine
+            .name
+            .strip_prefix(prefix)
+            .and_then(|index| index.parse::<u32>().ok())
+    };
+    machines.sort_by(|a, b| {
+        let key = |machine: &Machine| (index(machine).is_none(), index(machine));
+        key(a)
+            .cmp(&key(b))
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| a.id.cmp(&b.id))
+    });
+    let mut destroy = machines.split_off((count as usize).min(machines.len()));
+    destroy.reverse();
+    let taken: Vec<_> = machines.iter().filter_map(index).collect();
+    let create = (0..)
+        .filter(|index| !taken.contains(index))
+        .take(count as usize - machines.len())
+        .map(|index| format!("{prefix}{index}"))
+        .collect();
+    ScalePlan {
+        create,
+        destroy,
+        keep: machines,
+    }
+}
+
+async fn scale(
+    app_name: AppName,
+    template: MachineConfig,
+    metadata_selector: Vec<(String, String)>,
+    counts: Vec<(Region, u32)>,
+) -> Result<ScaleReport, anyhow::Error> {
+    ensure!(
+        !metadata_selector.is_empty(),
+        "metadata selector must not be empty"
+    );
+    let mut template = resolve_guest_preset(template)?;
+    validate_config(&template)?;
+    let selector: BTreeMap<_, _> = metadata_selector.iter().cloned().collect();
+    let mut metadata: BTreeMap<_, _> = template
+        .metadata
+        .take()
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    metadata.extend(selector.clone());
+    template.metadata = Some(metadata.into_iter().collect());
+
+    let machines = list(
+        app_name.clone(),
+        Some(ListFilter {
+            metadata: metadata_selector,
+            region: None,
+            state: None,
+        }),
+    )
+    .await?;
+    // Plan all regions before changing anything.
+    let mut plans = Vec::new();
+    for (region, count) in counts {
+        ensure!(
+            plans.iter().all(|(planned, _)| *planned != region),
+            "region {} is listed more than once",
+            query_value(&region)?
+        );
+        let region_machines = machines
+            .iter()
+            .filter(|machine| {
+                machine.region == region
+                    && !matches!(
+                        machine.state,
+                        MachineState::Destroying | MachineState::Destroyed
+                    )
+            })
+            .cloned()
+            .collect();
+        let prefix = scale_name_prefix(&selector, region)?;
+        plans.push((region, plan_scale(&prefix, count, region_machines)));
+    }
+
+    let (pending_create, pending_destroy) = limit_scale_plans(&mut plans);
+    let mut report = ScaleReport {
+        created: Vec::new(),
+        destroyed: Vec::new(),
+        unchanged: Vec::new(),
+        pending_create,
+        pending_destroy: pending_destroy
+            .into_iter()
+            .map(|machine| machine.id)
+            .collect(),
+    };
+    for (region, plan) in plans {
+        report
+            .unchanged
+            .extend(plan.keep.into_iter().map(|machine| machine.id));
+        for machine_name in plan.create {
+            match create_machine(
+                app_name.clone(),
+                machine_name.clone(),
+                template.clone(),
+                Some(region),
+                None,
+            )
+            .await?
+            {
+                Creation::Created(machine) => report.created.push(machine.id),
+                // Created by a previous attempt, but not listed yet.
+                Creation::AlreadyExists(machine)
+                    if matches_selector(&machine, &selector, region) =>
+                {
+                    report.unchanged.push(machine.id)
+                }
+                Creation::AlreadyExists(machine) => bail!(
+                    "machine {machine_name} ({}) already exists, but does not match the metadata selector",
+                    machine.id
+                ),
+            }
+        }
+        for machine in plan.destroy {
+            stop_and_delete(&app_name, &machine).await?;
+            report.destroyed.push(machine.id);
+        }
+    }
+    Ok(report)
+}
+
 async fn exec(
     app_name: AppName,
     machine_id: MachineId,
     command: Vec<String>,
+    options: Option<ExecOptions>,
 ) -> Result<ExecResponse, anyhow::Error> {
     let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/exec");
-    let body = serde_json::json!({
-        "command": command,
+    let options = options.unwrap_or(ExecOptions {
+        stdin: None,
+        timeout_secs: None,
+        container: None,
     });
+    let request_payload = ExecRequestSer {
+        command,
+        stdin: options.stdin,
+        timeout: options.timeout_secs,
+        container: options.container,
+    };
     let request = request_with_api_token()?
         .method(Method::POST)
         .uri(url)
-        .json(&body)?;
+        .json(&request_payload)?;
     let response = Client::new().send(request).await?;
     let resp_status = response.status();
     let mut response = response.into_body();
@@ -225,182 +1082,1218 @@ async fn exec(
     }
 }
 
-async fn change_machine(
+async fn list_events(
     app_name: AppName,
     machine_id: MachineId,
-    url_suffix: &'static str,
-) -> Result<(), anyhow::Error> {
-    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/{url_suffix}");
-    send_request(url, Method::POST).await
+) -> Result<Vec<MachineEvent>, anyhow::Error> {
+    get_events(app_name, machine_id.clone())
+        .await?
+        .with_context(|| format!("machine {machine_id} not found"))
 }
 
-async fn delete(
+/// Get the events of the machine, `None` if the machine does not exist (anymore).
+async fn get_events(
     app_name: AppName,
     machine_id: MachineId,
-    force: bool,
-) -> Result<(), anyhow::Error> {
-    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}?force={force}");
-    send_request(url, Method::DELETE).await
-}
-
-async fn send_request(url: String, method: Method) -> Result<(), anyhow::Error> {
+) -> Result<Option<Vec<MachineEvent>>, anyhow::Error> {
+    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/events");
     let request = request_with_api_token()?
-        .method(method)
+        .method(Method::GET)
         .uri(url)
         .body(Body::empty())?;
-
     let response = Client::new().send(request).await?;
     let resp_status = response.status();
     let mut response = response.into_body();
     let response = response.str_contents().await?;
 
     if resp_status.is_success() {
-        Ok(())
+        let response: Vec<MachineEventSer> = serde_json::from_str(response)
+            .inspect_err(|_| eprintln!("cannot deserialize: {response}"))?;
+        Ok(Some(response.into_iter().map(MachineEvent::from).collect()))
+    } else if resp_status == StatusCode::NOT_FOUND {
+        Ok(None)
     } else {
-        Err(anyhow!("failed with status {resp_status}: {response}",))
+        eprintln!("Got error status {resp_status}");
+        Err(anyhow!("failed with status {resp_status}: {response}"))
     }
 }
 
-// Implementation of the vm interface for the component.
-impl Guest for Component {
-    fn list(app_name: String) -> Result<Vec<Machine>, String> {
-        (|| {
-            let app_name = AppName::new(app_name)?;
-            block_on(list(app_name))
-        })()
-        .map_err(|err| err.to_string())
-    }
+async fn get_metadata(
+    app_name: AppName,
+    machine_id: MachineId,
+) -> Result<Vec<(String, String)>, anyhow::Error> {
+    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/metadata");
+    let request = request_with_api_token()?
+        .method(Method::GET)
+        .uri(url)
+        .body(Body::empty())?;
+    let response = Client::new().send(request).await?;
+    let resp_status = response.status();
+    let mut response = response.into_body();
+    let response = response.str_contents().await?;
 
-    fn get(app_name: String, machine_id: String) -> Result<Option<Machine>, String> {
-        (|| {
-            let app_name = AppName::new(app_name)?;
-            let machine_id = MachineId::new(machine_id)?;
-            block_on(get(app_name, machine_id))
-        })()
-        .map_err(|err| err.to_string())
+    if resp_status.is_success() {
+        let response: BTreeMap<String, String> = serde_json::from_str(response)
+            .inspect_err(|_| eprintln!("cannot deserialize: {response}"))?;
+        Ok(response.into_iter().collect())
+    } else {
+        eprintln!("Got error status {resp_status}");
+        Err(anyhow!("failed with status {resp_status}: {response}"))
     }
+}
 
-    fn create(
-        app_name: String,
-        machine_name: String,
-        machine_config: MachineConfig,
-        region: Option<Region>,
-    ) -> Result<String, String> {
-        (|| {
+async fn cordon(
+    app_name: AppName,
+    machine_id: MachineId,
+    lease_nonce: Option<LeaseNonce>,
+) -> Result<(), anyhow::Error> {
+    change_machine(app_name.clone(), machine_id.clone(), "cordon", lease_nonce).await?;
+    let key = MetadataKey::new(CORDONED_METADATA_KEY.to_string())?;
+    set_metadata(app_name, machine_id, key, "true".to_string()).await
+}
+
+async fn uncordon(
+    app_name: AppName,
+    machine_id: MachineId,
+    lease_nonce: Option<LeaseNonce>,
+) -> Result<(), anyhow::Error> {
+    change_machine(
+        app_name.clone(),
+        machine_id.clone(),
+        "uncordon",
+        lease_nonce,
+    )
+    .await?;
+    let key = MetadataKey::new(CORDONED_METADATA_KEY.to_string())?;
+    delete_metadata(app_name, machine_id, key).await
+}
+
+async fn set_metadata(
+    app_name: AppName,
+    machine_id: MachineId,
+    key: MetadataKey,
+    value: String,
+) -> Result<(), anyhow::Error> {
+    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/metadata/{key}");
+    let body = serde_json::json!({
+        "value": value,
+    });
+    let request = request_with_api_token()?
+        .method(Method::POST)
+        .uri(url)
+        .json(&body)?;
+    let response = Client::new().send(request).await?;
+    let resp_status = response.status();
+
+    if resp_status.is_success() {
+        Ok(())
+    } else {
+        let mut response = response.into_body();
+        let response = response.str_contents().await?;
+        Err(anyhow!("failed with status {resp_status}: {response}"))
+    }
+}
+
+async fn delete_metadata(
+    app_name: AppName,
+    machine_id: MachineId,
+    key: MetadataKey,
+) -> Result<(), anyhow::Error> {
+    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/metadata/{key}");
+    let request = request_with_api_token()?
+        .method(Method::DELETE)
+        .uri(url)
+        .body(Body::empty())?;
+    let response = Client::new().send(request).await?;
+    let resp_status = response.status();
+    // Idempotency: If the key was already deleted, treat it a success.
+    if resp_status.is_success() || resp_status == StatusCode::NOT_FOUND {
+        Ok(())
+    } else {
+        let mut response = response.into_body();
+        let response = response.str_contents().await?;
+        Err(anyhow!("failed with status {resp_status}: {response}"))
+    }
+}
+
+/// Get the version history as raw JSON, see `MachineVersionSer`.
+async fn get_versions(
+    app_name: AppName,
+    machine_id: MachineId,
+) -> Result<Vec<serde_json::Value>, anyhow::Error> {
+    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/versions");
+    let request = request_with_api_token()?
+        .method(Method::GET)
+        .uri(url)
+        .body(Body::empty())?;
+    let response = Client::new().send(request).await?;
+    let resp_status = response.status();
+    let mut response = response.into_body();
+    let response = response.str_contents().await?;
+
+    if resp_status.is_success() {
+        let response: Vec<serde_json::Value> = serde_json::from_str(response)
+            .inspect_err(|_| eprintln!("cannot deserialize: {response}"))?;
+        Ok(response)
+    } else {
+        eprintln!("Got error status {resp_status}");
+        Err(anyhow!("failed with status {resp_status}: {response}"))
+    }
+}
+
+async fn list_versions(
+    app_name: AppName,
+    machine_id: MachineId,
+) -> Result<Vec<MachineVersion>, anyhow::Error> {
+    let mut versions = get_versions(app_name, machine_id)
+        .await?
+        .into_iter()
+        .map(|version| {
+            serde_json::from_value::<MachineVersionSer>(version.clone())
+                .inspect_err(|_| eprintln!("cannot deserialize: {version}"))
+                .map(MachineVersion::from)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    // Version IDs are ULIDs, sorting them sorts by creation time.
+    versions.sort_by(|a, b| b.version.cmp(&a.version));
+    Ok(versions)
+}
+
+/// Find the configuration of the version in the raw version history.
+fn version_config(
+    versions: Vec<serde_json::Value>,
+    version: &str,
+) -> Result<serde_json::Value, anyhow::Error> {
+    let mut machine_version = versions
+        .into_iter()
+        .find(|machine_version| machine_version["version"] == version)
+        .with_context(|| format!("version {version} not found"))?;
+    match machine_version["user_config"].take() {
+        config @ serde_json::Value::Object(_) => Ok(config),
+        other => bail!("version {version} has no configuration: {other}"),
+    }
+}
+
+async fn rollback(
+    app_name: AppName,
+    machine_id: MachineId,
+    version: String,
+    lease_nonce: Option<LeaseNonce>,
+) -> Result<(), anyhow::Error> {
+    // The stored configuration is sent back as is, including fields not modeled by `MachineConfig`.
+    let versions = get_versions(app_name.clone(), machine_id.clone()).await?;
+    let machine_config = version_config(versions, &version)
+        .with_context(|| format!("cannot roll back machine {machine_id}"))?;
+    update_raw(
+        app_name,
+        machine_id,
+        machine_config,
+        None,
+        lease_nonce,
+        None,
+    )
+    .await?;
+    Ok(())
+}
+
+/// Signals accepted by the `signal` endpoint.
+const SIGNALS: [&str; 13] = [
+    "SIGABRT", "SIGALRM", "SIGFPE", "SIGHUP", "SIGILL", "SIGINT", "SIGKILL", "SIGPIPE", "SIGQUIT",
+    "SIGSEGV", "SIGTERM", "SIGTRAP", "SIGUSR1",
+];
+
+async fn signal(
+    app_name: AppName,
+    machine_id: MachineId,
+    signal: String,
+) -> Result<(), anyhow::Error> {
+    ensure!(
+        SIGNALS.contains(&signal.as_str()),
+        "unsupported signal `{signal}`, expected one of {}",
+        SIGNALS.join(", ")
+    );
+    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/signal");
+    let body = serde_json::json!({
+        "signal": signal,
+    });
+    let request = request_with_api_token()?
+        .method(Method::POST)
+        .uri(url)
+        .json(&body)?;
+    let response = Client::new().send(request).await?;
+    let resp_status = response.status();
+
+    if resp_status.is_success() {
+        Ok(())
+    } else {
+        let mut response = response.into_body();
+        let response = response.str_contents().await?;
+        Err(anyhow!("failed with status {resp_status}: {response}"))
+    }
+}
+
+async fn ps(app_name: AppName, machine_id: MachineId) -> Result<Vec<Process>, anyhow::Error> {
+    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/ps");
+    let request = request_with_api_token()?
+        .method(Method::GET)
+        .uri(url)
+        .body(Body::empty())?;
+    let response = Client::new().send(request).await?;
+    let resp_status = response.status();
+    let mut response = response.into_body();
+    let response = response.str_contents().await?;
+
+    if resp_status.is_success() {
+        let response: Vec<Process> = serde_json::from_str(response)
+            .inspect_err(|_| eprintln!("cannot deserialize: {response}"))?;
+        Ok(response)
+    } else {
+        eprintln!("Got error status {resp_status}");
+        Err(anyhow!("failed with status {resp_status}: {response}"))
+    }
+}
+
+/// Maximum `timeout` of the wait endpoint.
+pub(crate) const WAIT_TIMEOUT_MAX_SECS: u32 = 60;
+
+/// Map the state to the value accepted by the `wait` endpoint.
+fn wait_state(state: MachineState) -> Result<&'static str, anyhow::Error> {
+    match state {
+        MachineState::Started => Ok("started"),
+        MachineState::Stopped => Ok("stopped"),
+        MachineState::Suspended => Ok("suspended"),
+        MachineState::Destroyed => Ok("destroyed"),
+        other => bail!(
+            "cannot wait for state {other:?}, expected one of started, stopped, suspended, destroyed"
+        ),
+    }
+}
+
+fn wait_url(
+    app_name: &AppName,
+    machine_id: &MachineId,
+    state: MachineState,
+    instance_id: Option<InstanceId>,
+    timeout_secs: Option<u32>,
+) -> Result<String, anyhow::Error> {
+    let mut url = format!(
+        "{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/wait?state={state}",
+        state = wait_state(state)?
+    );
+    if let Some(instance_id) = instance_id {
+        url.push_str(&format!("&instance_id={instance_id}"));
+    }
+    if let Some(timeout_secs) = timeout_secs {
+        ensure!(
+            timeout_secs <= WAIT_TIMEOUT_MAX_SECS,
+            "timeout must be at most {WAIT_TIMEOUT_MAX_SECS}s, got {timeout_secs}s"
+        );
+        url.push_str(&format!("&timeout={timeout_secs}"));
+    }
+    Ok(url)
+}
+
+pub(crate) async fn wait(
+    app_name: AppName,
+    machine_id: MachineId,
+    state: MachineState,
+    instance_id: Option<InstanceId>,
+    timeout_secs: Option<u32>,
+) -> Result<WaitOutcome, anyhow::Error> {
+    let url = wait_url(&app_name, &machine_id, state, instance_id, timeout_secs)?;
+    let request = request_with_api_token()?
+        .method(Method::GET)
+        .uri(url)
+        .body(Body::empty())?;
+    let response = Client::new().send(request).await?;
+    let resp_status = response.status();
+
+    if resp_status.is_success() {
+        Ok(WaitOutcome::Reached)
+    } else if resp_status == StatusCode::REQUEST_TIMEOUT {
+        Ok(WaitOutcome::TimedOut)
+    } else {
+        let mut response = response.into_body();
+        let response = response.str_contents().await?;
+        eprintln!("Got error status {resp_status}");
+        Err(anyhow!("failed with status {resp_status}: {response}"))
+    }
+}
+
+/// Find the exit details in the newest `exit` event.
+fn last_exit(events: Vec<MachineEvent>) -> Option<ExitEvent> {
+    events
+        .into_iter()
+        .filter(|event| event.type_ == "exit")
+        .max_by_key(|event| event.timestamp)
+        .and_then(|event| event.exit_event)
+}
+
+/// Maximum `timeout-secs` of `run-job`, leaving time for creating the machine and reading its events
+/// within the 900s lock expiry configured for this activity.
+const JOB_TIMEOUT_MAX_SECS: u32 = 840;
+
+fn validate_job_timeout(timeout_secs: u32) -> Result<(), anyhow::Error> {
+    ensure!(
+        timeout_secs <= JOB_TIMEOUT_MAX_SECS,
+        "timeout must be at most {JOB_TIMEOUT_MAX_SECS}s, got {timeout_secs}s"
+    );
+    Ok(())
+}
+
+async fn run_job(
+    app_name: AppName,
+    machine_name: String,
+    machine_config: MachineConfig,
+    region: Option<Region>,
+    timeout_secs: u32,
+    destroy: bool,
+) -> Result<JobResult, anyhow::Error> {
+    validate_job_timeout(timeout_secs)?;
+    let auto_destroy = machine_config.auto_destroy == Some(true);
+    let machine = create(app_name.clone(), machine_name, machine_config, region, None).await?;
+    let machine_id = MachineId::new(machine.id)?;
+    let instance_id = InstanceId::new(machine.instance_id)?;
+    let state = if auto_destroy {
+        MachineState::Destroyed
+    } else {
+        MachineState::Stopped
+    };
+    let started_at = Instant::now();
+    let timed_out = loop {
+        let remaining = u64::from(timeout_secs).saturating_sub(started_at.elapsed().as_secs());
+        if remaining == 0 {
+            break true;
+        }
+        let timeout_secs = remaining.min(u64::from(WAIT_TIMEOUT_MAX_SECS)) as u32;
+        let outcome = wait(
+            app_name.clone(),
+            machine_id.clone(),
+            state,
+            Some(instance_id.clone()),
+            Some(timeout_secs),
+        )
+        .await?;
+        if matches!(outcome, WaitOutcome::Reached) {
+            break false;
+        }
+    };
+    let mut result = JobResult {
+        machine_id: machine_id.to_string(),
+        exit: None,
+        timed_out,
+        destroyed: auto_destroy && !timed_out,
+    };
+    if timed_out {
+        return Ok(result);
+    }
+    // Events of an auto-destroyed machine may already be gone.
+    result.exit = get_events(app_name.clone(), machine_id.clone())
+        .await?
+        .and_then(last_exit);
+    if destroy && !result.destroyed {
+        delete(app_name, machine_id, true, None).await?;
+        result.destroyed = true;
+    }
+    Ok(result)
+}
+
+async fn change_machine(
+    app_name: AppName,
+    machine_id: MachineId,
+    url_suffix: &'static str,
+    lease_nonce: Option<LeaseNonce>,
+) -> Result<(), anyhow::Error> {
+    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/{url_suffix}");
+    let request = request_with_lease_nonce(lease_nonce.as_ref())?
+        .method(Method::POST)
+        .uri(url)
+        .body(Body::empty())?;
+    send_request(request).await
+}
+
+async fn stop(
+    app_name: AppName,
+    machine_id: MachineId,
+    stop_config: Option<StopConfig>,
+    lease_nonce: Option<LeaseNonce>,
+) -> Result<(), anyhow::Error> {
+    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/stop");
+    let request = request_with_lease_nonce(lease_nonce.as_ref())?
+        .method(Method::POST)
+        .uri(url);
+    let request = match stop_config {
+        Some(stop_config) => request.json(&stop_config)?,
+        None => request.body(Body::empty())?,
+    };
+    send_request(request).await
+}
+
+async fn delete(
+    app_name: AppName,
+    machine_id: MachineId,
+    force: bool,
+    lease_nonce: Option<LeaseNonce>,
+) -> Result<(), anyhow::Error> {
+    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}?force={force}");
+    let request = request_with_lease_nonce(lease_nonce.as_ref())?
+        .method(Method::DELETE)
+        .uri(url)
+        .body(Body::empty())?;
+    let response = Client::new().send(request).await?;
+    let resp_status = response.status();
+    // Idempotency: If the machine was already deleted, treat it a success.
+    if resp_status.is_success() || resp_status == StatusCode::NOT_FOUND {
+        Ok(())
+    } else {
+        let mut response = response.into_body();
+        let response = response.str_contents().await?;
+        Err(anyhow!("failed with status {resp_status}: {response}"))
+    }
+}
+
+/// Lifecycle transition that is skipped if the machine is already in the target state.
+#[derive(Clone, Copy, Debug)]
+enum Transition {
+    Start,
+    Stop,
+    Suspend,
+    Delete,
+}
+
+impl Transition {
+    /// Check if the machine is in the target state or on its way there.
+    fn is_done(self, state: Option<MachineState>) -> bool {
+        matches!(
+            (self, state),
+            (
+                Transition::Start,
+                Some(MachineState::Starting | MachineState::Started)
+            ) | (
+                Transition::Stop,
+                Some(MachineState::Stopping | MachineState::Stopped)
+            ) | (
+                Transition::Suspend,
+                Some(MachineState::Suspending | MachineState::Suspended)
+            ) | (
+                Transition::Delete,
+                None | Some(MachineState::Destroying | MachineState::Destroyed)
+            )
+        )
+    }
+}
+
+/// Send the `request` unless the machine is already in the target state of the `transition`.
+async fn transition(
+    app_name: AppName,
+    machine_id: MachineId,
+    transition: Transition,
+    request: impl Future<Output = Result<(), anyhow::Error>>,
+) -> Result<TransitionResult, anyhow::Error> {
+    let prior_state = get(app_name.clone(), machine_id.clone())
+        .await?
+        .map(|machine| machine.state);
+    if transition.is_done(prior_state) {
+        return Ok(TransitionResult {
+            prior_state,
+            changed: false,
+        });
+    }
+    ensure!(prior_state.is_some(), "machine {machine_id} not found");
+    if let Err(err) = request.await {
+        // The machine might have reached the target state concurrently.
+        let current_state = get(app_name, machine_id)
+            .await?
+            .map(|machine| machine.state);
+        if !transition.is_done(current_state) {
+            return Err(err);
+        }
+    }
+    Ok(TransitionResult {
+        prior_state,
+        changed: true,
+    })
+}
+
+async fn send_request(request: Request<Body>) -> Result<(), anyhow::Error> {
+    let response = Client::new().send(request).await?;
+    let resp_status = response.status();
+    let mut response = response.into_body();
+    let response = response.str_contents().await?;
+
+    if resp_status.is_success() {
+        Ok(())
+    } else {
+        Err(anyhow!("failed with status {resp_status}: {response}",))
+    }
+}
+
+const LEASE_NONCE_HEADER: &str = "fly-machine-lease-nonce";
+
+fn request_with_lease_nonce(
+    lease_nonce: Option<&LeaseNonce>,
+) -> Result<request::Builder, anyhow::Error> {
+    let builder = request_with_api_token()?;
+    Ok(match lease_nonce {
+        Some(lease_nonce) => builder.header(LEASE_NONCE_HEADER, lease_nonce.as_ref()),
+        None => builder,
+    })
+}
+
+/// Acquire a new lease, or refresh the existing one if `lease_nonce` is set.
+async fn post_lease(
+    app_name: AppName,
+    machine_id: MachineId,
+    lease_nonce: Option<LeaseNonce>,
+    request_payload: LeaseRequestSer,
+) -> Result<Lease, anyhow::Error> {
+    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/lease");
+    let request = request_with_lease_nonce(lease_nonce.as_ref())?
+        .method(Method::POST)
+        .uri(url)
+        .json(&request_payload)?;
+    let response = Client::new().send(request).await?;
+    let resp_status = response.status();
+    let mut response = response.into_body();
+    let response = response.str_contents().await?;
+
+    if resp_status.is_success() {
+        let response: LeaseResponseSer = serde_json::from_str(response)
+            .inspect_err(|_| eprintln!("cannot deserialize: {response}"))?;
+        Ok(response.data)
+    } else {
+        eprintln!("Got error status {resp_status}");
+        Err(anyhow!("failed with status {resp_status}: {response}"))
+    }
+}
+
+async fn get_lease(
+    app_name: AppName,
+    machine_id: MachineId,
+) -> Result<Option<Lease>, anyhow::Error> {
+    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/lease");
+    let request = request_with_api_token()?
+        .method(Method::GET)
+        .uri(url)
+        .body(Body::empty())?;
+    let response = Client::new().send(request).await?;
+    let resp_status = response.status();
+    let mut response = response.into_body();
+    let response = response.str_contents().await?;
+
+    if resp_status.is_success() {
+        let response: LeaseResponseSer = serde_json::from_str(response)
+            .inspect_err(|_| eprintln!("cannot deserialize: {response}"))?;
+        Ok(Some(response.data))
+    } else if resp_status == StatusCode::NOT_FOUND {
+        Ok(None)
+    } else {
+        eprintln!("Got error status {resp_status}");
+        Err(anyhow!("failed with status {resp_status}: {response}"))
+    }
+}
+
+async fn release_lease(
+    app_name: AppName,
+    machine_id: MachineId,
+    lease_nonce: LeaseNonce,
+) -> Result<(), anyhow::Error> {
+    let url = format!("{API_BASE_URL}/apps/{app_name}/machines/{machine_id}/lease");
+    let request = request_with_lease_nonce(Some(&lease_nonce))?
+        .method(Method::DELETE)
+        .uri(url)
+        .body(Body::empty())?;
+    let response = Client::new().send(request).await?;
+    let resp_status = response.status();
+    // Idempotency: If the lease was already released or expired, treat it a success.
+    if resp_status.is_success() || resp_status == StatusCode::NOT_FOUND {
+        Ok(())
+    } else {
+        let mut response = response.into_body();
+        let response = response.str_contents().await?;
+        Err(anyhow!("failed with status {resp_status}: {response}"))
+    }
+}
+
+// Implementation of the vm interface for the component.
+impl Guest for Component {
+    fn list(app_name: String) -> Result<Vec<Machine>, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            block_on(list(app_name, None))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn list_filtered(app_name: String, filter: ListFilter) -> Result<Vec<Machine>, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            block_on(list(app_name, Some(filter)))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn get(app_name: String, machine_id: String) -> Result<Option<Machine>, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            block_on(get(app_name, machine_id))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn create(
+        app_name: String,
+        machine_name: String,
+        machine_config: MachineConfig,
+        region: Option<Region>,
+        options: Option<CreateOptions>,
+    ) -> Result<Machine, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            block_on(create(
+                app_name,
+                machine_name,
+                machine_config,
+                region,
+                options,
+            ))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn plan(
+        app_name: String,
+        machine_id: String,
+        desired: MachineConfig,
+    ) -> Result<ConfigDiff, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            block_on(plan(app_name, machine_id, desired))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn update(
+        app_name: String,
+        machine_id: String,
+        machine_config: MachineConfig,
+        region: Option<Region>,
+        lease_nonce: Option<String>,
+        options: Option<CreateOptions>,
+    ) -> Result<Machine, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            let lease_nonce = lease_nonce.map(LeaseNonce::new).transpose()?;
+            block_on(update(
+                app_name,
+                machine_id,
+                machine_config,
+                region,
+                lease_nonce,
+                options,
+            ))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn clone(
+        app_name: String,
+        source_id: String,
+        new_name: String,
+        region: Region,
+        overrides: Option<CloneOverrides>,
+    ) -> Result<Machine, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let source_id = MachineId::new(source_id)?;
+            block_on(clone(app_name, source_id, new_name, region, overrides))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn ensure(
+        app_name: String,
+        machine_name: String,
+        machine_config: MachineConfig,
+        region: Option<Region>,
+    ) -> Result<EnsureResult, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            block_on(ensure(app_name, machine_name, machine_config, region))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn scale(
+        app_name: String,
+        template: MachineConfig,
+        metadata_selector: Vec<(String, String)>,
+        counts: Vec<(Region, u32)>,
+    ) -> Result<ScaleReport, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            block_on(scale(app_name, template, metadata_selector, counts))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn run_job(
+        app_name: String,
+        machine_name: String,
+        machine_config: MachineConfig,
+        region: Option<Region>,
+        timeout_secs: u32,
+        destroy: bool,
+    ) -> Result<JobResult, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            block_on(run_job(
+                app_name,
+                machine_name,
+                machine_config,
+                region,
+                timeout_secs,
+                destroy,
+            ))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn stop(
+        app_name: String,
+        machine_id: String,
+        stop_config: Option<StopConfig>,
+        lease_nonce: Option<String>,
+    ) -> Result<TransitionResult, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            let lease_nonce = lease_nonce.map(LeaseNonce::new).transpose()?;
+            let request = stop(
+                app_name.clone(),
+                machine_id.clone(),
+                stop_config,
+                lease_nonce,
+            );
+            block_on(transition(app_name, machine_id, Transition::Stop, request))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn suspend(
+        app_name: String,
+        machine_id: String,
+        lease_nonce: Option<String>,
+    ) -> Result<TransitionResult, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            let lease_nonce = lease_nonce.map(LeaseNonce::new).transpose()?;
+            let request =
+                change_machine(app_name.clone(), machine_id.clone(), "suspend", lease_nonce);
+            block_on(transition(
+                app_name,
+                machine_id,
+                Transition::Suspend,
+                request,
+            ))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn start(
+        app_name: String,
+        machine_id: String,
+        lease_nonce: Option<String>,
+    ) -> Result<TransitionResult, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            let lease_nonce = lease_nonce.map(LeaseNonce::new).transpose()?;
+            let request =
+                change_machine(app_name.clone(), machine_id.clone(), "start", lease_nonce);
+            block_on(transition(app_name, machine_id, Transition::Start, request))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn restart(
+        app_name: String,
+        machine_id: String,
+        lease_nonce: Option<String>,
+    ) -> Result<(), String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            let lease_nonce = lease_nonce.map(LeaseNonce::new).transpose()?;
+            block_on(change_machine(app_name, machine_id, "restart", lease_nonce))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn delete(
+        app_name: String,
+        machine_id: String,
+        force: bool,
+        lease_nonce: Option<String>,
+    ) -> Result<TransitionResult, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            let lease_nonce = lease_nonce.map(LeaseNonce::new).transpose()?;
+            let request = delete(app_name.clone(), machine_id.clone(), force, lease_nonce);
+            block_on(transition(
+                app_name,
+                machine_id,
+                Transition::Delete,
+                request,
+            ))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn exec(
+        app_name: String,
+        machine_id: String,
+        command: Vec<String>,
+        options: Option<ExecOptions>,
+    ) -> Result<ExecResponse, String> {
+        (|| {
             let app_name = AppName::new(app_name)?;
-            block_on(create(app_name, machine_name, machine_config, region))
+            let machine_id = MachineId::new(machine_id)?;
+            block_on(exec(app_name, machine_id, command, options))
         })()
         .map_err(|err| err.to_string())
     }
 
-    fn update(
+    fn exec_check_success(
         app_name: String,
         machine_id: String,
-        machine_config: MachineConfig,
-        region: Option<Region>,
+        command: Vec<String>,
+        options: Option<ExecOptions>,
+    ) -> Result<ExecResponse, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            block_on(async {
+                let resp = exec(app_name, machine_id, command, options).await?;
+                if resp.exit_code == Some(0) {
+                    Ok(resp)
+                } else {
+                    bail!("non-successful exit status - {resp:?}")
+                }
+            })
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn cordon(
+        app_name: String,
+        machine_id: String,
+        lease_nonce: Option<String>,
+    ) -> Result<(), String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            let lease_nonce = lease_nonce.map(LeaseNonce::new).transpose()?;
+            block_on(cordon(app_name, machine_id, lease_nonce))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn uncordon(
+        app_name: String,
+        machine_id: String,
+        lease_nonce: Option<String>,
     ) -> Result<(), String> {
         (|| {
             let app_name = AppName::new(app_name)?;
             let machine_id = MachineId::new(machine_id)?;
-            block_on(update(app_name, machine_id, machine_config, region))
+            let lease_nonce = lease_nonce.map(LeaseNonce::new).transpose()?;
+            block_on(uncordon(app_name, machine_id, lease_nonce))
         })()
         .map_err(|err| err.to_string())
     }
 
-    fn stop(app_name: String, machine_id: String) -> Result<(), String> {
+    fn signal(app_name: String, machine_id: String, signal_name: String) -> Result<(), String> {
         (|| {
             let app_name = AppName::new(app_name)?;
             let machine_id = MachineId::new(machine_id)?;
-            block_on(change_machine(app_name, machine_id, "stop"))
+            block_on(signal(app_name, machine_id, signal_name))
         })()
         .map_err(|err| err.to_string())
     }
 
-    fn suspend(app_name: String, machine_id: String) -> Result<(), String> {
+    fn ps(app_name: String, machine_id: String) -> Result<Vec<Process>, String> {
         (|| {
             let app_name = AppName::new(app_name)?;
             let machine_id = MachineId::new(machine_id)?;
-            block_on(change_machine(app_name, machine_id, "suspend"))
+            block_on(ps(app_name, machine_id))
         })()
         .map_err(|err| err.to_string())
     }
 
-    fn start(app_name: String, machine_id: String) -> Result<(), String> {
+    fn wait(
+        app_name: String,
+        machine_id: String,
+        state: MachineState,
+        instance_id: Option<String>,
+        timeout_secs: Option<u32>,
+    ) -> Result<WaitOutcome, String> {
         (|| {
             let app_name = AppName::new(app_name)?;
             let machine_id = MachineId::new(machine_id)?;
-            block_on(change_machine(app_name, machine_id, "start"))
+            let instance_id = instance_id.map(InstanceId::new).transpose()?;
+            block_on(wait(app_name, machine_id, state, instance_id, timeout_secs))
         })()
         .map_err(|err| err.to_string())
     }
 
-    fn restart(app_name: String, machine_id: String) -> Result<(), String> {
+    fn list_events(app_name: String, machine_id: String) -> Result<Vec<MachineEvent>, String> {
         (|| {
             let app_name = AppName::new(app_name)?;
             let machine_id = MachineId::new(machine_id)?;
-            block_on(change_machine(app_name, machine_id, "restart"))
+            block_on(list_events(app_name, machine_id))
         })()
         .map_err(|err| err.to_string())
     }
 
-    fn delete(app_name: String, machine_id: String, force: bool) -> Result<(), String> {
+    fn get_metadata(app_name: String, machine_id: String) -> Result<Vec<(String, String)>, String> {
         (|| {
             let app_name = AppName::new(app_name)?;
             let machine_id = MachineId::new(machine_id)?;
-            block_on(delete(app_name, machine_id, force))
+            block_on(get_metadata(app_name, machine_id))
         })()
         .map_err(|err| err.to_string())
     }
 
-    fn exec(
+    fn set_metadata(
         app_name: String,
         machine_id: String,
-        command: Vec<String>,
-    ) -> Result<ExecResponse, String> {
+        key: String,
+        value: String,
+    ) -> Result<(), String> {
         (|| {
             let app_name = AppName::new(app_name)?;
             let machine_id = MachineId::new(machine_id)?;
-            block_on(exec(app_name, machine_id, command))
+            let key = MetadataKey::new(key)?;
+            block_on(set_metadata(app_name, machine_id, key, value))
         })()
         .map_err(|err| err.to_string())
     }
 
-    fn exec_check_success(
+    fn delete_metadata(app_name: String, machine_id: String, key: String) -> Result<(), String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            let key = MetadataKey::new(key)?;
+            block_on(delete_metadata(app_name, machine_id, key))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn list_versions(app_name: String, machine_id: String) -> Result<Vec<MachineVersion>, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            block_on(list_versions(app_name, machine_id))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn rollback(
         app_name: String,
         machine_id: String,
-        command: Vec<String>,
-    ) -> Result<ExecResponse, String> {
+        version: String,
+        lease_nonce: Option<String>,
+    ) -> Result<(), String> {
         (|| {
             let app_name = AppName::new(app_name)?;
             let machine_id = MachineId::new(machine_id)?;
-            block_on(async {
-                let resp = exec(app_name, machine_id, command).await?;
-                if resp.exit_code == Some(0) {
-                    Ok(resp)
-                } else {
-                    bail!("non-successful exit status - {resp:?}")
-                }
-            })
+            let lease_nonce = lease_nonce.map(LeaseNonce::new).transpose()?;
+            block_on(rollback(app_name, machine_id, version, lease_nonce))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn acquire_lease(
+        app_name: String,
+        machine_id: String,
+        ttl_secs: Option<u32>,
+        description: Option<String>,
+    ) -> Result<Lease, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            let request_payload = LeaseRequestSer {
+                ttl: ttl_secs,
+                description,
+            };
+            block_on(post_lease(app_name, machine_id, None, request_payload))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn refresh_lease(
+        app_name: String,
+        machine_id: String,
+        nonce: String,
+        ttl_secs: Option<u32>,
+    ) -> Result<Lease, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            let nonce = LeaseNonce::new(nonce)?;
+            let request_payload = LeaseRequestSer {
+                ttl: ttl_secs,
+                description: None,
+            };
+            block_on(post_lease(
+                app_name,
+                machine_id,
+                Some(nonce),
+                request_payload,
+            ))
+        })()
+        .map_err(|err| err.to_string())
+    }
+
+    fn release_lease(app_name: String, machine_id: String, nonce: String) -> Result<(), String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            let nonce = LeaseNonce::new(nonce)?;
+            block_on(release_lease(app_name, machine_id, nonce))
         })()
         .map_err(|err| err.to_string())
     }
+
+    fn get_lease(app_name: String, machine_id: String) -> Result<Option<Lease>, String> {
+        (|| {
+            let app_name = AppName::new(app_name)?;
+            let machine_id = MachineId::new(machine_id)?;
+            block_on(get_lease(app_name, machine_id))
+        })()
+        .map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_util {
+    use crate::generated::exports::obelisk_flyio::activity_fly_http::machines::Machine;
+    use std::collections::BTreeMap;
+
+    /// Build a started machine named after its ID, as reported by the API.
+    pub(crate) fn test_machine(id: &str, image: &str, metadata: &[(&str, &str)]) -> Machine {
+        let metadata: BTreeMap<_, _> = metadata.iter().copied().collect();
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "name": id,
+            "state": "started",
+            "region": "ams",
+            "instance_id": "01K4SR42ZPDHHCN70QNZKVPK48",
+            "config": {"image": image, "metadata": metadata},
+            "created_at": "2025-09-10T12:03:04Z",
+            "updated_at": "2025-09-10T12:03:07Z",
+            "host_status": "ok"
+        }))
+        .expect("machine fixture must be valid")
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::ser::ResponseErrorSer;
+    use super::ser::{
+        CreateOptionsSer, LeaseResponseSer, MachineCreateRequestSer, MachineEventSer,
+        MachineVersionSer, ResponseErrorSer,
+    };
+    use super::test_util::test_machine;
+    use super::{
+        JOB_TIMEOUT_MAX_SECS, ScalePlan, Transition, WAIT_TIMEOUT_MAX_SECS, clone_config,
+        config_differs, diff_config, last_exit, limit_scale_plans, list_query, matches_selector,
+        plan_scale, resolve_guest_preset, scale_name_prefix, validate_config, validate_job_timeout,
+        version_config, wait_state, wait_url, with_cordon_state,
+    };
     use crate::generated::{
-        exports::obelisk_flyio::activity_fly_http::machines::Machine,
+        exports::obelisk_flyio::activity_fly_http::machines::{
+            AutostopMode, CheckState, CheckType, CloneOverrides, ContainerDependencyCondition,
+            CpuKind, CreateOptions, GuestPreset, ListFilter, Machine, MachineConfig, MachineEvent,
+            MachineState, MachineVersion, Process, ServiceConfig, StopConfig,
+        },
         obelisk_flyio::activity_fly_http::regions::Region,
     };
+    use crate::{AppName, MachineId};
     use insta::assert_debug_snapshot;
     use serde_json::json;
+    use std::collections::BTreeMap;
+
+    /// Build a configuration of the `nginx` image with additional fields.
+    fn config_with(fields: serde_json::Value) -> MachineConfig {
+        let mut config = json!({"image": "nginx"});
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(config).unwrap()
+    }
 
     #[test]
     fn region_ser() {
         assert_eq!("\"ams\"", serde_json::to_string(&Region::Ams).unwrap());
     }
 
+    #[test]
+    fn schedule_ser() {
+        let request = MachineCreateRequestSer {
+            name: "job".to_string(),
+            config: serde_json::to_value(config_with(json!({"schedule": "daily"}))).unwrap(),
+            region: None,
+            options: CreateOptionsSer::default(),
+        };
+        let request = serde_json::to_value(&request).unwrap();
+        assert_eq!("daily", request["config"]["schedule"]);
+    }
+
+    #[test]
+    fn create_options_should_be_flattened() {
+        let request = MachineCreateRequestSer {
+            name: "standby".to_string(),
+            config: serde_json::from_value(json!({"image": "alpine"})).unwrap(),
+            region: None,
+            options: Some(CreateOptions {
+                skip_launch: Some(true),
+                skip_service_registration: None,
+                lease_ttl_secs: Some(30),
+            })
+            .into(),
+        };
+        let request = serde_json::to_value(&request).unwrap();
+        assert_eq!(true, request["skip_launch"]);
+        assert_eq!(30, request["lease_ttl"]);
+        assert!(request.get("skip_service_registration").is_none());
+    }
+
+    #[test]
+    fn stop_config_timeout_should_be_a_duration() {
+        let stop_config = StopConfig {
+            signal: Some("SIGTERM".to_string()),
+            timeout: Some(90),
+        };
+        assert_eq!(
+            json!({"signal": "SIGTERM", "timeout": "90s"}),
+            serde_json::to_value(&stop_config).unwrap()
+        );
+        let stop_config: StopConfig =
+            serde_json::from_value(json!({"signal": "SIGINT", "timeout": "1m30s"})).unwrap();
+        assert_eq!(Some(90), stop_config.timeout);
+        let stop_config: StopConfig = serde_json::from_value(json!({})).unwrap();
+        assert_eq!(None, stop_config.timeout);
+        assert!(serde_json::from_value::<StopConfig>(json!({"timeout": "30 seconds"})).is_err());
+    }
+
     #[test]
     fn region_de() {
         assert_matches::assert_matches!(serde_json::from_str("\"ams\"").unwrap(), Region::Ams);
@@ -414,6 +2307,653 @@ mod tests {
         assert_eq!("32876249a30918", id);
     }
 
+    #[test]
+    fn env_should_be_serialized_as_object() {
+        let config: MachineConfig = serde_json::from_value(json!({
+            "image": "nginx",
+            "env": {"B": "2", "A": "1"}
+        }))
+        .unwrap();
+        assert_eq!(
+            Some(vec![
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "2".to_string())
+            ]),
+            config.env
+        );
+        let config = serde_json::to_value(&config).unwrap();
+        assert_eq!(json!({"A": "1", "B": "2"}), config["env"]);
+    }
+
+    #[test]
+    fn checks_serialization() {
+        let config: MachineConfig = serde_json::from_value(json!({
+            "image": "nginx",
+            "checks": {
+                "alive": {"type": "tcp", "port": 8080, "interval": "15s"}
+            }
+        }))
+        .unwrap();
+        let (name, check) = &config.checks.as_ref().unwrap()[0];
+        assert_eq!("alive", name);
+        assert_matches::assert_matches!(check.type_, CheckType::Tcp);
+        let config = serde_json::to_value(&config).unwrap();
+        assert_eq!("tcp", config["checks"]["alive"]["type"]);
+    }
+
+    #[test]
+    fn validate_config_should_require_single_file_source() {
+        let config = |file| config_with(json!({"files": [file]}));
+        validate_config(&config(json!({"guest_path": "/etc/a", "secret_name": "A"}))).unwrap();
+        validate_config(&config(
+            json!({"guest_path": "/etc/a", "raw_value": "YQ=="}),
+        ))
+        .unwrap();
+        assert!(validate_config(&config(json!({"guest_path": "/etc/a"}))).is_err());
+        assert!(
+            validate_config(&config(
+                json!({"guest_path": "/etc/a", "raw_value": "YQ==", "secret_name": "A"})
+            ))
+            .is_err()
+        );
+        assert!(validate_config(&config(json!({"guest_path": "a", "secret_name": "A"}))).is_err());
+    }
+
+    #[test]
+    fn guest_preset_should_be_resolved_and_validated() {
+        let config = |guest| config_with(json!({"guest": guest}));
+        let mut preset = config(json!({}));
+        preset.guest.as_mut().unwrap().preset = Some(GuestPreset::Performance2x);
+        let resolved = resolve_guest_preset(preset.clone()).unwrap();
+        let guest = resolved.guest.as_ref().unwrap();
+        assert_eq!(Some(CpuKind::Performance), guest.cpu_kind);
+        assert_eq!(Some(2), guest.cpus);
+        assert_eq!(Some(4096), guest.memory_mb);
+        assert!(guest.preset.is_none());
+        validate_config(&resolved).unwrap();
+
+        preset.guest.as_mut().unwrap().memory_mb = Some(256);
+        let err = validate_config(&resolve_guest_preset(preset.clone()).unwrap()).unwrap_err();
+        assert_eq!(
+            "performance guests with 2 CPUs need between 4096MB and 16384MB of memory, got 256MB",
+            err.to_string()
+        );
+
+        preset.guest.as_mut().unwrap().cpus = Some(1);
+        assert!(resolve_guest_preset(preset).is_err());
+
+        validate_config(&config(json!({"memory_mb": 2048}))).unwrap();
+        assert!(validate_config(&config(json!({"memory_mb": 300}))).is_err());
+        assert!(validate_config(&config(json!({"cpu_kind": "shared", "cpus": 16}))).is_err());
+    }
+
+    #[test]
+    fn containers_serialization_and_validation() {
+        let config = json!({
+            "image": "app",
+            "containers": [
+                {
+                    "name": "app",
+                    "image": "app",
+                    "env": {"PORT": "8080"},
+                    "depends_on": [{"name": "log-shipper", "condition": "started"}]
+                },
+                {
+                    "name": "migrate",
+                    "image": "app",
+                    "cmd": ["migrate"],
+                    "files": [{"guest_path": "/etc/db.conf", "secret_name": "DB"}]
+                },
+                {
+                    "name": "log-shipper",
+                    "image": "vector",
+                    "depends_on": [{"name": "migrate", "condition": "exited_successfully"}]
+                }
+            ]
+        });
+        let mut machine_config: MachineConfig = serde_json::from_value(config.clone()).unwrap();
+        validate_config(&machine_config).unwrap();
+        let containers = machine_config.containers.as_ref().unwrap();
+        assert_matches::assert_matches!(
+            containers[2].depends_on.as_ref().unwrap()[0].condition,
+            ContainerDependencyCondition::ExitedSuccessfully
+        );
+        let serialized = serde_json::to_value(&machine_config).unwrap();
+        assert_eq!(
+            config["containers"][0]["env"],
+            serialized["containers"][0]["env"]
+        );
+        assert_eq!(
+            "exited_successfully",
+            serialized["containers"][2]["depends_on"][0]["condition"]
+        );
+
+        machine_config.containers.as_mut().unwrap()[2].name = "app".to_string();
+        assert!(validate_config(&machine_config).is_err());
+        machine_config.containers.as_mut().unwrap()[2].name = "log-shipper".to_string();
+        machine_config.containers.as_mut().unwrap()[2]
+            .depends_on
+            .as_mut()
+            .unwrap()[0]
+            .name = "db".to_string();
+        assert!(validate_config(&machine_config).is_err());
+    }
+
+    #[test]
+    fn autostop_deserialization() {
+        let service = |autostop| -> ServiceConfig {
+            serde_json::from_value(json!({
+                "internal_port": 8080,
+                "protocol": "tcp",
+                "ports": [{"port": 443, "handlers": ["tls", "http"]}],
+                "autostop": autostop
+            }))
+            .unwrap()
+        };
+        assert_matches::assert_matches!(service(json!(false)).autostop, Some(AutostopMode::Off));
+        assert_matches::assert_matches!(service(json!(true)).autostop, Some(AutostopMode::Stop));
+        assert_matches::assert_matches!(
+            service(json!("suspend")).autostop,
+            Some(AutostopMode::Suspend)
+        );
+        assert_matches::assert_matches!(service(json!(null)).autostop, None);
+        assert_eq!(
+            "suspend",
+            serde_json::to_value(service(json!("suspend"))).unwrap()["autostop"]
+        );
+    }
+
+    #[test]
+    fn validate_config_should_check_port_ranges() {
+        let config = |port| {
+            config_with(json!({
+                "services": [{"internal_port": 8080, "protocol": "tcp", "ports": [port]}]
+            }))
+        };
+        validate_config(&config(json!({"port": 80, "handlers": []}))).unwrap();
+        validate_config(&config(
+            json!({"start_port": 8000, "end_port": 8010, "handlers": []}),
+        ))
+        .unwrap();
+        assert!(validate_config(&config(json!({"handlers": []}))).is_err());
+        assert!(
+            validate_config(&config(
+                json!({"port": 80, "start_port": 8000, "end_port": 8010, "handlers": []})
+            ))
+            .is_err()
+        );
+        assert!(
+            validate_config(&config(
+                json!({"start_port": 8010, "end_port": 8000, "handlers": []})
+            ))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn cordon_state_should_be_derived_from_metadata() {
+        let machine = test_machine("080155df097248", "nginx", &[("cordoned", "true")]);
+        assert!(!machine.cordoned);
+        assert!(with_cordon_state(machine).cordoned);
+        let machine = test_machine("080155df097248", "nginx", &[("cordoned", "false")]);
+        assert!(!with_cordon_state(machine).cordoned);
+    }
+
+    #[test]
+    fn check_status_deserialization() {
+        let mut machine =
+            serde_json::to_value(test_machine("080155df097248", "nginx", &[])).unwrap();
+        machine["checks"] = json!([
+            {
+                "name": "alive",
+                "status": "passing",
+                "output": "Success",
+                "updated_at": "2025-09-10T12:03:27.31Z"
+            }
+        ]);
+        let machine: Machine = serde_json::from_value(machine).unwrap();
+        let checks = machine.checks.unwrap();
+        assert_eq!("alive", checks[0].name);
+        assert_matches::assert_matches!(checks[0].status, CheckState::Passing);
+    }
+
+    #[test]
+    fn machine_events_deserialization() {
+        let response = json!([
+            {
+                "id": "01K4SR7XJ5A4B0ZQ8V6W9CVZ0M",
+                "type": "exit",
+                "status": "stopped",
+                "request": {
+                    "exit_event": {
+                        "exit_code": 137,
+                        "exited_at": "2025-09-10T12:05:07.12Z",
+                        "guest_exit_code": 0,
+                        "guest_signal": -1,
+                        "oom_killed": true,
+                        "requested_stop": false,
+                        "restarting": false,
+                        "signal": -1
+                    },
+                    "restart_count": 0
+                },
+                "source": "flyd",
+                "timestamp": 1757505907120u64
+            },
+            {
+                "id": "01K4SR45V7PBDQ7HBHEAJ6C9YA",
+                "type": "start",
+                "status": "started",
+                "request": {},
+                "source": "flyd",
+                "timestamp": 1757505787751u64
+            },
+            {
+                "id": "01K4SR432JJXA85KC2RB63ANTA",
+                "type": "launch",
+                "status": "created",
+                "source": "user",
+                "timestamp": 1757505784914u64
+            }
+        ]);
+        let events: Vec<MachineEventSer> = serde_json::from_value(response).unwrap();
+        let events: Vec<MachineEvent> = events.into_iter().map(MachineEvent::from).collect();
+        assert_eq!(3, events.len());
+        assert_eq!("exit", events[0].type_);
+        let exit_event = events[0].exit_event.as_ref().unwrap();
+        assert_eq!(Some(137), exit_event.exit_code);
+        assert_eq!(Some(true), exit_event.oom_killed);
+        assert!(events[1].exit_event.is_none());
+        assert!(events[2].exit_event.is_none());
+    }
+
+    #[test]
+    fn last_exit_should_pick_newest_exit_event() {
+        let event = |timestamp: u64, exit_code: i32| -> MachineEvent {
+            let event: MachineEventSer = serde_json::from_value(json!({
+                "id": format!("event-{timestamp}"),
+                "type": "exit",
+                "status": "stopped",
+                "request": {"exit_event": {"exit_code": exit_code}},
+                "source": "flyd",
+                "timestamp": timestamp
+            }))
+            .unwrap();
+            MachineEvent::from(event)
+        };
+        let exit = last_exit(vec![event(1, 1), event(3, 0), event(2, 2)]).unwrap();
+        assert_eq!(Some(0), exit.exit_code);
+        assert!(last_exit(Vec::new()).is_none());
+    }
+
+    #[test]
+    fn process_deserialization() {
+        let response = json!([
+            {
+                "command": "nginx -g daemon off;",
+                "cpu": 120,
+                "directory": "/",
+                "listen_sockets": [{"address": "0.0.0.0:80", "proto": "tcp"}],
+                "pid": 313,
+                "rss": 8232960,
+                "rtime": 1520,
+                "stime": 90
+            }
+        ]);
+        let processes: Vec<Process> = serde_json::from_value(response).unwrap();
+        assert_eq!(313, processes[0].pid);
+        assert_eq!(
+            "0.0.0.0:80",
+            processes[0].listen_sockets.as_ref().unwrap()[0].address
+        );
+    }
+
+    #[test]
+    fn machine_version_deserialization() {
+        let response = json!([
+            {
+                "version": "01K4SR42ZPDHHCN70QNZKVPK48",
+                "user_config": {"image": "getobelisk/obelisk:0.24.1-ubuntu"}
+            }
+        ]);
+        let versions: Vec<MachineVersionSer> = serde_json::from_value(response).unwrap();
+        let versions: Vec<MachineVersion> =
+            versions.into_iter().map(MachineVersion::from).collect();
+        assert_eq!("01K4SR42ZPDHHCN70QNZKVPK48", versions[0].version);
+        assert_eq!(Some(1757505784822), versions[0].created_at_ms);
+        assert_eq!("getobelisk/obelisk:0.24.1-ubuntu", versions[0].config.image);
+    }
+
+    #[test]
+    fn version_config_should_keep_unmodeled_fields() {
+        let versions = vec![
+            json!({"version": "01K4SR432JJXA85KC2RB63ANTA", "user_config": {"image": "nginx:1"}}),
+            json!({
+                "version": "01K4SR45V7PBDQ7HBHEAJ6C9YA",
+                "user_config": {"image": "nginx:2", "processes": [{"cmd": ["nginx"]}]}
+            }),
+        ];
+        let config = version_config(versions.clone(), "01K4SR45V7PBDQ7HBHEAJ6C9YA").unwrap();
+        assert_eq!(
+            json!({"image": "nginx:2", "processes": [{"cmd": ["nginx"]}]}),
+            config
+        );
+        assert!(version_config(versions, "01K4SR7XJ5A4B0ZQ8V6W9CVZ0M").is_err());
+    }
+
+    #[test]
+    fn lease_deserialization() {
+        let response = json!({
+            "status": "success",
+            "data": {
+                "nonce": "2ccbe6ff8dc9",
+                "expires_at": 1757505847,
+                "owner": "user@example.com",
+                "description": "update",
+                "version": "01K4SR45V7PBDQ7HBHEAJ6C9YA"
+            }
+        });
+        let response: LeaseResponseSer = serde_json::from_value(response).unwrap();
+        assert_eq!("2ccbe6ff8dc9", response.data.nonce);
+        assert_eq!(1757505847, response.data.expires_at);
+    }
+
+    #[test]
+    fn list_query_should_contain_all_filters() {
+        let filter = ListFilter {
+            metadata: vec![("role".to_string(), "web".to_string())],
+            region: Some(Region::Ams),
+            state: Some(MachineState::Started),
+        };
+        assert_eq!(
+            "?metadata.role=web&region=ams&state=started",
+            list_query(&filter).unwrap()
+        );
+    }
+
+    #[test]
+    fn list_query_should_encode_metadata_values() {
+        let filter = |key: &str, value: &str| ListFilter {
+            metadata: vec![(key.to_string(), value.to_string())],
+            region: None,
+            state: None,
+        };
+        assert_eq!(
+            "?metadata.version=v1.2",
+            list_query(&filter("version", "v1.2")).unwrap()
+        );
+        assert_eq!(
+            "?metadata.role=web%26region%3Dams%20a%2Fb",
+            list_query(&filter("role", "web&region=ams a/b")).unwrap()
+        );
+        assert!(list_query(&filter("role&region", "web")).is_err());
+    }
+
+    #[test]
+    fn transition_is_done() {
+        assert!(Transition::Start.is_done(Some(MachineState::Started)));
+        assert!(Transition::Start.is_done(Some(MachineState::Starting)));
+        assert!(!Transition::Start.is_done(Some(MachineState::Stopped)));
+        assert!(!Transition::Start.is_done(None));
+        assert!(Transition::Stop.is_done(Some(MachineState::Stopped)));
+        assert!(!Transition::Stop.is_done(Some(MachineState::Suspended)));
+        assert!(Transition::Suspend.is_done(Some(MachineState::Suspended)));
+        assert!(Transition::Delete.is_done(Some(MachineState::Destroyed)));
+        assert!(Transition::Delete.is_done(None));
+        assert!(!Transition::Delete.is_done(Some(MachineState::Started)));
+    }
+
+    #[test]
+    fn wait_state_should_reject_transient_states() {
+        assert_eq!("started", wait_state(MachineState::Started).unwrap());
+        assert_eq!("destroyed", wait_state(MachineState::Destroyed).unwrap());
+        assert!(wait_state(MachineState::Starting).is_err());
+    }
+
+    #[test]
+    fn wait_url_should_reject_timeout_over_limit() {
+        let app_name = AppName::new("app".to_string()).unwrap();
+        let machine_id = MachineId::new("080155df097248".to_string()).unwrap();
+        let url = wait_url(
+            &app_name,
+            &machine_id,
+            MachineState::Stopped,
+            None,
+            Some(WAIT_TIMEOUT_MAX_SECS),
+        )
+        .unwrap();
+        assert!(url.ends_with("/wait?state=stopped&timeout=60"), "{url}");
+        let err = wait_url(
+            &app_name,
+            &machine_id,
+            MachineState::Stopped,
+            None,
+            Some(WAIT_TIMEOUT_MAX_SECS + 1),
+        )
+        .unwrap_err();
+        assert_eq!("timeout must be at most 60s, got 61s", err.to_string());
+    }
+
+    #[test]
+    fn validate_job_timeout_should_stay_within_lock_expiry() {
+        validate_job_timeout(JOB_TIMEOUT_MAX_SECS).unwrap();
+        let err = validate_job_timeout(JOB_TIMEOUT_MAX_SECS + 1).unwrap_err();
+        assert_eq!("timeout must be at most 840s, got 841s", err.to_string());
+    }
+
+    #[test]
+    fn clone_config_should_require_target_volume() {
+        let source = json!({
+            "image": "nginx",
+            "env": {"A": "1"},
+            "mounts": [{"volume": "vol_source", "path": "/data", "size_gb": 1}],
+            "metadata": {"role": "web", "fly_platform_version": "v2"},
+            "processes": [{"cmd": ["nginx"]}]
+        });
+        assert!(clone_config(source.clone(), None).is_err());
+
+        let overrides = CloneOverrides {
+            image: Some("nginx:2".to_string()),
+            env: None,
+            guest: None,
+            volume: Some("vol_target".to_string()),
+        };
+        let cloned = clone_config(source, Some(overrides)).unwrap();
+        assert_eq!(
+            json!({
+                "image": "nginx:2",
+                "env": {"A": "1"},
+                "mounts": [{"volume": "vol_target", "path": "/data", "size_gb": 1}],
+                "metadata": {"fly_platform_version": "v2"},
+                "processes": [{"cmd": ["nginx"]}]
+            }),
+            cloned
+        );
+    }
+
+    #[test]
+    fn plan_scale_should_keep_lowest_indexes() {
+        let machine = |name: &str| test_machine(name, "nginx", &[("role", "web")]);
+        let selector = BTreeMap::from([("role".to_string(), "web".to_string())]);
+        let prefix = scale_name_prefix(&selector, Region::Ams).unwrap();
+        assert_eq!("role-web-ams-", prefix);
+        let machines = vec![
+            machine("manual"),
+            machine("role-web-ams-2"),
+            machine("role-web-ams-0"),
+        ];
+
+        let plan = plan_scale(&prefix, 1, machines.clone());
+        assert!(plan.create.is_empty());
+        let ids = |machines: &[Machine]| -> Vec<String> {
+            machines.iter().map(|machine| machine.id.clone()).collect()
+        };
+        assert_eq!(vec!["role-web-ams-0"], ids(&plan.keep));
+        assert_eq!(vec!["manual", "role-web-ams-2"], ids(&plan.destroy));
+
+        let plan = plan_scale(&prefix, 5, machines);
+        assert_eq!(vec!["role-web-ams-1", "role-web-ams-3"], plan.create);
+        assert_eq!(
+            vec!["role-web-ams-0", "role-web-ams-2", "manual"],
+            ids(&plan.keep)
+        );
+        assert!(plan.destroy.is_empty());
+    }
+
+    #[test]
+    fn limit_scale_plans_should_cap_changes_per_call() {
+        let machine = |name: &str| test_machine(name, "nginx", &[("role", "web")]);
+        let mut plans = vec![
+            (
+                Region::Ams,
+                ScalePlan {
+                    create: (0..8)
+                        .map(|index| format!("role-web-ams-{index}"))
+                        .collect(),
+                    destroy: Vec::new(),
+                    keep: Vec::new(),
+                },
+            ),
+            (
+                Region::Fra,
+                ScalePlan {
+                    create: Vec::new(),
+                    destroy: (0..4)
+                        .rev()
+                        .map(|index| machine(&format!("role-web-fra-{index}")))
+                        .collect(),
+                    keep: Vec::new(),
+                },
+            ),
+        ];
+        let (pending_create, pending_destroy) = limit_scale_plans(&mut plans);
+        assert!(pending_create.is_empty());
+        assert_eq!(8, plans[0].1.create.len());
+        let ids = |machines: &[Machine]| -> Vec<String> {
+            machines.iter().map(|machine| machine.id.clone()).collect()
+        };
+        assert_eq!(
+            vec!["role-web-fra-3", "role-web-fra-2"],
+            ids(&plans[1].1.destroy)
+        );
+        assert_eq!(
+            vec!["role-web-fra-1", "role-web-fra-0"],
+            ids(&pending_destroy)
+        );
+    }
+
+    #[test]
+    fn matches_selector_should_require_all_pairs_and_region() {
+        let selector = BTreeMap::from([("role".to_string(), "web".to_string())]);
+        let managed = test_machine("role-web-ams-0", "nginx", &[("role", "web"), ("tier", "1")]);
+        assert!(matches_selector(&managed, &selector, Region::Ams));
+        assert!(!matches_selector(&managed, &selector, Region::Fra));
+        let manual = test_machine("role-web-ams-0", "nginx", &[("role", "db")]);
+        assert!(!matches_selector(&manual, &selector, Region::Ams));
+        let bare = test_machine("role-web-ams-0", "nginx", &[]);
+        assert!(!matches_selector(&bare, &selector, Region::Ams));
+    }
+
+    #[test]
+    fn config_differs_should_ignore_defaults() {
+        let desired: MachineConfig = serde_json::from_value(json!({
+            "image": "nginx",
+            "guest": {"cpu_kind": "shared", "cpus": 1},
+            "env": {"A": "1"}
+        }))
+        .unwrap();
+        let live: MachineConfig = serde_json::from_value(json!({
+            "image": "nginx",
+            "init": {},
+            "guest": {"cpu_kind": "shared", "cpus": 1, "memory_mb": 256},
+            "env": {"A": "1"},
+            "metadata": {"fly_platform_version": "v2"}
+        }))
+        .unwrap();
+        assert!(!config_differs(&desired, &live).unwrap());
+
+        let mut changed_image = desired.clone();
+        changed_image.image = "nginx:2".to_string();
+        assert!(config_differs(&changed_image, &live).unwrap());
+
+        let mut changed_guest = desired.clone();
+        changed_guest.guest.as_mut().unwrap().cpus = Some(2);
+        assert!(config_differs(&changed_guest, &live).unwrap());
+
+        let mut removed_env = desired.clone();
+        removed_env.env = Some(vec![]);
+        assert!(config_differs(&removed_env, &live).unwrap());
+
+        // The API omits `false` and other zero values.
+        let mut zero_values = desired.clone();
+        zero_values.auto_destroy = Some(false);
+        zero_values.guest.as_mut().unwrap().kernel_args = Some(vec![]);
+        assert!(!config_differs(&zero_values, &live).unwrap());
+        zero_values.auto_destroy = Some(true);
+        assert!(config_differs(&zero_values, &live).unwrap());
+    }
+
+    #[test]
+    fn diff_config_should_list_changes() {
+        let live: MachineConfig = serde_json::from_value(json!({
+            "image": "nginx",
+            "guest": {"cpu_kind": "shared", "cpus": 1, "memory_mb": 256},
+            "env": {"A": "1", "B": "2"},
+            "restart": {"policy": "always"},
+            "mounts": [{"volume": "vol_1", "path": "/data"}]
+        }))
+        .unwrap();
+        let desired: MachineConfig = serde_json::from_value(json!({
+            "image": "nginx:2",
+            "guest": {"cpu_kind": "shared", "cpus": 2},
+            "env": {"A": "3", "C": "4"},
+            "restart": {"policy": "no"},
+            "mounts": [{"volume": "vol_1", "path": "/data"}]
+        }))
+        .unwrap();
+        let diff = diff_config(&desired, &live).unwrap();
+        assert!(diff.changed);
+        let image = diff.image.unwrap();
+        assert_eq!(
+            ("nginx", "nginx:2"),
+            (image.live.as_str(), image.desired.as_str())
+        );
+        assert_eq!(vec!["C"], diff.env_added);
+        assert_eq!(vec!["B"], diff.env_removed);
+        assert_eq!(vec!["A"], diff.env_changed);
+        let guest = diff.guest.unwrap();
+        assert_eq!(Some(1), guest.live.unwrap().cpus);
+        assert_eq!(Some(2), guest.desired.cpus);
+        assert!(diff.mounts.is_none());
+        assert!(diff.services.is_none());
+        assert_eq!(vec!["restart"], diff.other_fields);
+
+        let diff = diff_config(&live, &live).unwrap();
+        assert!(!diff.changed);
+    }
+
+    #[test]
+    fn diff_config_should_match_zero_values_with_omitted_fields() {
+        let live = config_with(json!({
+            "guest": {"cpu_kind": "shared", "cpus": 1, "memory_mb": 256},
+            "services": [{"protocol": "tcp", "internal_port": 80, "ports": []}]
+        }));
+        let desired = config_with(json!({
+            "auto_destroy": false,
+            "guest": {"cpu_kind": "shared", "cpus": 1, "kernel_args": []},
+            "services": [{"protocol": "tcp", "internal_port": 80, "ports": [], "min_machines_running": 0}]
+        }));
+        let diff = diff_config(&desired, &live).unwrap();
+        assert!(!diff.changed);
+        assert!(diff.guest.is_none());
+        assert!(diff.services.is_none());
+        assert!(diff.other_fields.is_empty());
+
+        let desired = config_with(json!({"auto_destroy": true}));
+        let diff = diff_config(&desired, &live).unwrap();
+        assert_eq!(vec!["auto-destroy"], diff.other_fields);
+    }
+
     #[test]
     fn machine_deserialization() {
         let json = r#"
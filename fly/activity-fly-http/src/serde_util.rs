@@ -0,0 +1,122 @@
+/// (De)serialize `option<list<tuple<string, T>>>` as an optional JSON object.
+pub(crate) mod optional_map {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub(crate) fn serialize<T, S>(
+        value: &Option<Vec<(String, T)>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        T: Serialize,
+        S: Serializer,
+    {
+        match value {
+            Some(entries) => serializer.collect_map(entries.iter().map(|(k, v)| (k, v))),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, T, D>(
+        deserializer: D,
+    ) -> Result<Option<Vec<(String, T)>>, D::Error>
+    where
+        T: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let map: Option<BTreeMap<String, T>> = Option::deserialize(deserializer)?;
+        Ok(map.map(|map| map.into_iter().collect()))
+    }
+}
+
+/// Deserialize `option<autostop-mode>` from either a bool or a string.
+pub(crate) mod optional_autostop {
+    use crate::generated::exports::obelisk_flyio::activity_fly_http::machines::AutostopMode;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        value: &Option<AutostopMode>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<AutostopMode>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum AutostopSer {
+            Bool(bool),
+            Mode(AutostopMode),
+        }
+        Ok(
+            Option::<AutostopSer>::deserialize(deserializer)?.map(|autostop| match autostop {
+                AutostopSer::Bool(false) => AutostopMode::Off,
+                AutostopSer::Bool(true) => AutostopMode::Stop,
+                AutostopSer::Mode(mode) => mode,
+            }),
+        )
+    }
+}
+
+/// (De)serialize `option<u64>` seconds as a Go duration string such as `30s` or `1m30s`.
+/// A bare number would be interpreted by the API as nanoseconds.
+pub(crate) mod optional_duration_secs {
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(
+        value: &Option<u64>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(secs) => serializer.serialize_str(&format!("{secs}s")),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u64>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum DurationSer {
+            Nanos(u64),
+            Text(String),
+        }
+        match Option::<DurationSer>::deserialize(deserializer)? {
+            None => Ok(None),
+            Some(DurationSer::Nanos(nanos)) => Ok(Some(nanos / 1_000_000_000)),
+            Some(DurationSer::Text(text)) => parse_secs(&text)
+                .map(Some)
+                .ok_or_else(|| D::Error::custom(format!("invalid duration `{text}`"))),
+        }
+    }
+
+    /// Parse a Go duration string, rounding down to whole seconds.
+    fn parse_secs(text: &str) -> Option<u64> {
+        let mut rest = text;
+        let mut secs = 0.0;
+        while !rest.is_empty() {
+            let number_len = rest.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+            let number: f64 = rest[..number_len].parse().ok()?;
+            rest = &rest[number_len..];
+            let unit_len = rest
+                .find(|c: char| c.is_ascii_digit() || c == '.')
+                .unwrap_or(rest.len());
+            let unit = match &rest[..unit_len] {
+                "h" => 3600.0,
+                "m" => 60.0,
+                "s" => 1.0,
+                "ms" => 1e-3,
+                "us" | "µs" => 1e-6,
+                "ns" => 1e-9,
+                _ => return None,
+            };
+            secs += number * unit;
+            rest = &rest[unit_len..];
+        }
+        Some(secs as u64)
+    }
+}